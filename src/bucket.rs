@@ -1,22 +1,36 @@
 use std::io;
 use std::path::Path;
+use std::path::PathBuf;
 
+use chrono::DateTime;
+use chrono::Utc;
 use failure::Error;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use tokio::fs;
 use tokio::io::AsyncWriteExt as _;
+use tokio::sync::Mutex;
 
-#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
-enum VersioningPolicy {
+use super::dir;
+use super::error::S3Error;
+use super::lifecycle::Rule;
+use super::multipart;
+use super::xml::Xml;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VersioningPolicy {
+    /// Never enabled; each key only has one version.
     Off,
     On,
-    FileNotFound,
+    /// Was enabled; existing history is kept, but new writes replace each other.
+    #[serde(alias = "FileNotFound")]
+    Suspended,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
-enum LifecyclePolicy {
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LifecyclePolicy {
     Keep,
+    /// Old versions, and deleted keys, are removed by the garbage collector.
     CollectOlder,
 }
 
@@ -24,8 +38,108 @@ enum LifecyclePolicy {
 pub struct BucketConfig {
     versioning: VersioningPolicy,
     lifecycle: LifecyclePolicy,
+    /// Buckets from before this was recorded appear to have just been created.
+    #[serde(default = "Utc::now")]
+    created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<Rule>,
+    /// Where the bucket was created; older buckets are in the default region.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    /// Whether anonymous requests may read, and list, the bucket's objects.
+    #[serde(default)]
+    public_read: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateBucketConfiguration {
+    location_constraint: Option<String>,
+}
+
+impl Default for BucketConfig {
+    fn default() -> BucketConfig {
+        BucketConfig {
+            versioning: VersioningPolicy::Off,
+            lifecycle: LifecyclePolicy::Keep,
+            created: Utc::now(),
+            rules: Vec::new(),
+            region: None,
+            public_read: false,
+        }
+    }
+}
+
+impl BucketConfig {
+    pub fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    pub fn versioning(&self) -> VersioningPolicy {
+        self.versioning
+    }
+
+    pub fn set_versioning(&mut self, versioning: VersioningPolicy) {
+        self.versioning = versioning;
+    }
+
+    pub fn lifecycle(&self) -> LifecyclePolicy {
+        self.lifecycle
+    }
+
+    pub fn set_lifecycle(&mut self, lifecycle: LifecyclePolicy) {
+        self.lifecycle = lifecycle;
+    }
+
+    /// The lifecycle configuration's rules, which may be empty.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.rules = rules;
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    pub fn set_region(&mut self, region: String) {
+        self.region = Some(region);
+    }
+
+    pub fn public_read(&self) -> bool {
+        self.public_read
+    }
+
+    pub fn set_public_read(&mut self, public_read: bool) {
+        self.public_read = public_read;
+    }
 }
 
+/// Parse the (optional) body of a CreateBucket request, for the region it asks for, if any.
+pub fn location_from_xml(body: &[u8]) -> Option<Option<String>> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Some(None);
+    }
+    let config: CreateBucketConfiguration = serde_xml_rs::from_reader(body).ok()?;
+    Some(
+        config
+            .location_constraint
+            .filter(|region| !region.is_empty()),
+    )
+}
+
+/// Render a GetBucketLocation response, which is empty for the original region.
+pub fn location(region: &str) -> String {
+    let mut xml = Xml::new("LocationConstraint");
+    if "us-east-1" != region {
+        xml.content(region);
+    }
+    xml.finish()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Name(String);
 
 impl Name {
@@ -37,22 +151,73 @@ impl Name {
             None
         }
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Where the bucket's config and objects live.
+    pub fn dir(&self, storage: &Path) -> PathBuf {
+        storage.join(&self.0)
+    }
 }
 
 pub async fn get_config(storage: &Path, bucket: &Name) -> Result<Option<BucketConfig>, Error> {
-    let mut dir = storage.to_path_buf();
-    dir.push(&bucket.0);
+    let mut dir = bucket.dir(storage);
     dir.push("config.json");
     match fs::read(&dir).await {
         Ok(c) => Ok(Some(serde_json::from_slice(&c)?)),
         Err(ref e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
-        Err(e) => return Err(e.into()),
+        Err(e) => Err(e.into()),
     }
 }
 
+/// All the buckets with a config, sorted by name.
+pub async fn list(storage: &Path) -> Result<Vec<(Name, BucketConfig)>, Error> {
+    let mut ret = Vec::new();
+    let mut entries = fs::read_dir(storage).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = match entry.file_name().to_str().and_then(Name::from) {
+            Some(name) => name,
+            None => continue,
+        };
+        if let Some(config) = get_config(storage, &name).await? {
+            ret.push((name, config));
+        }
+    }
+    ret.sort_by(|(left, _), (right, _)| left.0.cmp(&right.0));
+    Ok(ret)
+}
+
+/// Remove the bucket's config, and its directory, if it has no keys or uploads in progress.
+///
+/// Deleted keys with history still count. Anything else left in the directory (e.g. delete
+/// markers, or unreferenced versions) is also removed.
+pub async fn delete(storage: &Path, meta_lock: &Mutex<()>, bucket: &Name) -> Result<(), Error> {
+    let dir = bucket.dir(storage);
+
+    // no keys can be written between checking and removing
+    let _writing = meta_lock.lock().await;
+
+    let keys = dir::list(&dir).await?;
+    if keys.iter().any(|meta| !meta.only_tombstones()) || multipart::in_progress(&dir).await? {
+        return Err(S3Error::BucketNotEmpty.into());
+    }
+
+    // uploads don't take the lock, so this fails if one has been started since we checked
+    match fs::remove_dir(dir.join(".uploads")).await {
+        Ok(()) => (),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+        Err(_) => return Err(S3Error::BucketNotEmpty.into()),
+    }
+
+    fs::remove_file(dir.join("config.json")).await?;
+    fs::remove_dir_all(&dir).await?;
+    Ok(())
+}
+
 pub async fn put_config(storage: &Path, bucket: &Name, config: &BucketConfig) -> Result<(), Error> {
-    let mut dir = storage.to_path_buf();
-    dir.push(&bucket.0);
+    let mut dir = bucket.dir(storage);
     fs::create_dir_all(&dir).await?;
     dir.push("config.json");
     let mut temp = super::temp::NamedTempFile::new_in(dir.parent().expect("just pushed")).await?;
@@ -65,13 +230,29 @@ pub async fn put_config(storage: &Path, bucket: &Name, config: &BucketConfig) ->
     Ok(())
 }
 
+/// Change the bucket's config, holding the `lock` so concurrent changes aren't lost.
+pub async fn update_config(
+    storage: &Path,
+    lock: &Mutex<()>,
+    bucket: &Name,
+    change: impl FnOnce(&mut BucketConfig),
+) -> Result<(), Error> {
+    let _writing = lock.lock().await;
+    let mut config = match get_config(storage, bucket).await? {
+        Some(config) => config,
+        None => return Err(S3Error::NoSuchBucket.into()),
+    };
+    change(&mut config);
+    put_config(storage, bucket, &config).await
+}
+
 // 3 to 63 lower case ascii letters or digits, dots and hyphens, with no double dots
 fn valid_bucket_name(name: &str) -> bool {
     if name.len() < 3 || name.len() > 63 {
         return false;
     }
 
-    if !name.starts_with(|c| alnum(c)) || !name.ends_with(|c| alnum(c)) {
+    if !name.starts_with(alnum) || !name.ends_with(alnum) {
         return false;
     }
 
@@ -102,3 +283,37 @@ fn naming() {
     assert!(valid_bucket_name("xn--wow-ee"));
     assert!(valid_bucket_name("xm--wow-ee"));
 }
+
+#[test]
+fn locating() {
+    assert_eq!(Some(None), location_from_xml(b""));
+    assert_eq!(
+        Some(Some("eu-west-2".to_string())),
+        location_from_xml(
+            br#"<CreateBucketConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+            <LocationConstraint>eu-west-2</LocationConstraint>
+            </CreateBucketConfiguration>"#
+        )
+    );
+    assert_eq!(None, location_from_xml(b"<potato"));
+
+    assert_eq!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <LocationConstraint xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         eu-west-2</LocationConstraint>",
+        location("eu-west-2")
+    );
+}
+
+#[test]
+fn old_configs() {
+    let config: BucketConfig =
+        serde_json::from_str(r#"{"versioning": "Off", "lifecycle": "Keep"}"#).expect("valid");
+    assert_eq!(VersioningPolicy::Off, config.versioning());
+    assert!(Utc::now() - config.created() < chrono::Duration::minutes(1));
+
+    let config: BucketConfig =
+        serde_json::from_str(r#"{"versioning": "On", "lifecycle": "CollectOlder"}"#)
+            .expect("valid");
+    assert_eq!(LifecyclePolicy::CollectOlder, config.lifecycle());
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use sha2::digest::FixedOutput as _;
+use sha2::digest::Input as _;
+
+use super::error::S3Error;
+use super::xml::Xml;
+
+/// The additional checksums a client can ask us to check, and keep.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Algorithm {
+    Crc32,
+    Crc32c,
+    Sha1,
+    Sha256,
+}
+
+const ALGORITHMS: [Algorithm; 4] = [
+    Algorithm::Crc32,
+    Algorithm::Crc32c,
+    Algorithm::Sha1,
+    Algorithm::Sha256,
+];
+
+impl Algorithm {
+    /// As in `x-amz-checksum-algorithm`.
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Crc32 => "CRC32",
+            Algorithm::Crc32c => "CRC32C",
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+        }
+    }
+
+    /// The header, or trailer, which carries the checksum.
+    fn header(self) -> &'static str {
+        match self {
+            Algorithm::Crc32 => "x-amz-checksum-crc32",
+            Algorithm::Crc32c => "x-amz-checksum-crc32c",
+            Algorithm::Sha1 => "x-amz-checksum-sha1",
+            Algorithm::Sha256 => "x-amz-checksum-sha256",
+        }
+    }
+
+    /// The length of the decoded checksum.
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Crc32 | Algorithm::Crc32c => 4,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+        }
+    }
+}
+
+/// The base64 checksums of a version's data, for whichever algorithms the uploader asked for.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksums {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crc32c: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha1: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+impl Checksums {
+    pub fn is_empty(&self) -> bool {
+        ALGORITHMS
+            .iter()
+            .all(|&algorithm| self.get(algorithm).is_none())
+    }
+
+    fn get(&self, algorithm: Algorithm) -> Option<&String> {
+        match algorithm {
+            Algorithm::Crc32 => self.crc32.as_ref(),
+            Algorithm::Crc32c => self.crc32c.as_ref(),
+            Algorithm::Sha1 => self.sha1.as_ref(),
+            Algorithm::Sha256 => self.sha256.as_ref(),
+        }
+    }
+
+    fn get_mut(&mut self, algorithm: Algorithm) -> &mut Option<String> {
+        match algorithm {
+            Algorithm::Crc32 => &mut self.crc32,
+            Algorithm::Crc32c => &mut self.crc32c,
+            Algorithm::Sha1 => &mut self.sha1,
+            Algorithm::Sha256 => &mut self.sha256,
+        }
+    }
+
+    /// The checksums of a multipart upload, as S3 gives them: for each algorithm every part has,
+    /// the checksum of the parts' (decoded) checksums, then `-<number of parts>`.
+    pub fn composite(parts: &[&Checksums]) -> Checksums {
+        let mut ret = Checksums::default();
+        if parts.is_empty() {
+            return ret;
+        }
+
+        for &algorithm in &ALGORITHMS {
+            let mut hasher = Hasher::new(&[algorithm]);
+            let mut sha = sha2::Sha256::default();
+            let mut decoded = 0;
+            for part in parts {
+                if let Some(value) = part.get(algorithm).and_then(|v| base64::decode(v).ok()) {
+                    hasher.input(&value);
+                    sha.input(&value);
+                    decoded += 1;
+                }
+            }
+
+            if parts.len() != decoded {
+                continue;
+            }
+
+            if let Some(value) = hasher.finish(&sha.fixed_result()).get(algorithm) {
+                *ret.get_mut(algorithm) = Some(format!("{}-{}", value, parts.len()));
+            }
+        }
+        ret
+    }
+
+    /// Write the `<Checksum>` element of a GetObjectAttributes response.
+    pub fn write_xml(&self, xml: &mut Xml) {
+        xml.open("Checksum");
+        for &algorithm in &ALGORITHMS {
+            if let Some(value) = self.get(algorithm) {
+                xml.text(&format!("Checksum{}", algorithm.name()), value);
+            }
+        }
+        xml.close("Checksum");
+    }
+}
+
+/// What the uploader told us the body should hash to.
+#[derive(Clone, Debug, Default)]
+pub struct Expected {
+    md5_base64: Option<String>,
+    /// Those sent as headers; trailing checksums only arrive after the body.
+    checksums: Checksums,
+    /// Those we need to compute, whether we've been told the answer yet or not.
+    algorithms: Vec<Algorithm>,
+}
+
+impl Expected {
+    pub fn from_headers(headers: &hyper::HeaderMap) -> Result<Expected, S3Error> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let mut expected = Expected::default();
+
+        if let Some(md5) = header("content-md5") {
+            match base64::decode(md5) {
+                Ok(ref md5) if 16 == md5.len() => (),
+                _ => return Err(S3Error::InvalidDigest),
+            }
+            expected.md5_base64 = Some(md5.to_string());
+        }
+
+        for &algorithm in &ALGORITHMS {
+            if let Some(value) = header(algorithm.header()) {
+                match base64::decode(value) {
+                    Ok(ref value) if algorithm.len() == value.len() => (),
+                    _ => return Err(S3Error::InvalidArgument),
+                }
+                *expected.checksums.get_mut(algorithm) = Some(value.to_string());
+                expected.compute(algorithm);
+            }
+        }
+
+        // checksums promised as trailers, or requested without a value
+        let trailers = header("x-amz-trailer").unwrap_or("");
+        for name in trailers.split(',').map(|name| name.trim()) {
+            for &algorithm in &ALGORITHMS {
+                if name.eq_ignore_ascii_case(algorithm.header()) {
+                    expected.compute(algorithm);
+                }
+            }
+        }
+
+        for name in &["x-amz-checksum-algorithm", "x-amz-sdk-checksum-algorithm"] {
+            let value = header(name).unwrap_or("");
+            for &algorithm in &ALGORITHMS {
+                if value.eq_ignore_ascii_case(algorithm.name()) {
+                    expected.compute(algorithm);
+                }
+            }
+        }
+
+        Ok(expected)
+    }
+
+    fn compute(&mut self, algorithm: Algorithm) {
+        if !self.algorithms.contains(&algorithm) {
+            self.algorithms.push(algorithm);
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        Hasher::new(&self.algorithms)
+    }
+
+    /// Compare what we computed with what we were told, in the headers or the `trailers`.
+    pub fn check(
+        &self,
+        md5_base64: &str,
+        computed: &Checksums,
+        trailers: &HashMap<String, String>,
+    ) -> Result<(), S3Error> {
+        if let Some(expected) = &self.md5_base64 {
+            if !same(expected, md5_base64) {
+                return Err(S3Error::BadDigest);
+            }
+        }
+
+        for &algorithm in &ALGORITHMS {
+            let expected = match self
+                .checksums
+                .get(algorithm)
+                .or_else(|| trailers.get(algorithm.header()))
+            {
+                Some(expected) => expected,
+                None => continue,
+            };
+
+            match computed.get(algorithm) {
+                Some(computed) if same(expected, computed) => (),
+                Some(_) => return Err(S3Error::BadDigest),
+                // a trailer we weren't told to expect
+                None => return Err(S3Error::InvalidRequest),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether two base64 strings have the same value.
+fn same(left: &str, right: &str) -> bool {
+    match (base64::decode(left), base64::decode(right)) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => false,
+    }
+}
+
+/// Computes the checksums which were asked for, alongside the md5 and sha256 we always compute.
+pub struct Hasher {
+    crc32: Option<crc32fast::Hasher>,
+    crc32c: Option<u32>,
+    sha1: Option<sha1::Sha1>,
+    sha256: bool,
+}
+
+impl Hasher {
+    fn new(algorithms: &[Algorithm]) -> Hasher {
+        let wanted = |algorithm| algorithms.contains(&algorithm);
+        Hasher {
+            crc32: if wanted(Algorithm::Crc32) {
+                Some(crc32fast::Hasher::new())
+            } else {
+                None
+            },
+            crc32c: if wanted(Algorithm::Crc32c) {
+                Some(0)
+            } else {
+                None
+            },
+            sha1: if wanted(Algorithm::Sha1) {
+                Some(sha1::Sha1::default())
+            } else {
+                None
+            },
+            sha256: wanted(Algorithm::Sha256),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        if let Some(crc32) = &mut self.crc32 {
+            crc32.update(data);
+        }
+        if let Some(crc32c) = &mut self.crc32c {
+            *crc32c = crc32c::crc32c_append(*crc32c, data);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.input(data);
+        }
+    }
+
+    pub fn finish(self, sha256: &[u8]) -> Checksums {
+        Checksums {
+            crc32: self
+                .crc32
+                .map(|crc32| base64::encode(&crc32.finalize().to_be_bytes())),
+            crc32c: self
+                .crc32c
+                .map(|crc32c| base64::encode(&crc32c.to_be_bytes())),
+            sha1: self.sha1.map(|sha1| base64::encode(&sha1.fixed_result())),
+            sha256: if self.sha256 {
+                Some(base64::encode(sha256))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[test]
+fn checking() {
+    use hyper::header::HeaderValue;
+
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(
+        "content-md5",
+        HeaderValue::from_static("XUFAKrxLKna5cZ2REBfFkg=="),
+    );
+    headers.insert("x-amz-checksum-crc32", HeaderValue::from_static("NhCmhg=="));
+    headers.insert(
+        "x-amz-trailer",
+        HeaderValue::from_static("x-amz-checksum-sha1"),
+    );
+    let expected = Expected::from_headers(&headers).expect("valid");
+
+    let mut hasher = expected.hasher();
+    hasher.input(b"hel");
+    hasher.input(b"lo");
+    let computed = hasher.finish(&[0u8; 32]);
+    assert_eq!(Some("NhCmhg==".to_string()), computed.crc32);
+    assert_eq!(
+        Some("qvTGHdzF6KLavt4PO0gs2a6pQ00=".to_string()),
+        computed.sha1
+    );
+    assert_eq!(None, computed.crc32c);
+    assert_eq!(None, computed.sha256);
+
+    let md5 = "XUFAKrxLKna5cZ2REBfFkg==";
+    let trailers = maplit::hashmap! {
+        "x-amz-checksum-sha1".to_string() => "qvTGHdzF6KLavt4PO0gs2a6pQ00=".to_string(),
+    };
+    assert_eq!(Ok(()), expected.check(md5, &computed, &trailers));
+    assert_eq!(Ok(()), expected.check(md5, &computed, &HashMap::new()));
+    assert_eq!(
+        Err(S3Error::BadDigest),
+        expected.check("1B2M2Y8AsgTpgAmY7PhCfg==", &computed, &trailers)
+    );
+
+    let wrong = maplit::hashmap! {
+        "x-amz-checksum-sha1".to_string() => base64::encode(&[0u8; 20]),
+    };
+    assert_eq!(
+        Err(S3Error::BadDigest),
+        expected.check(md5, &computed, &wrong)
+    );
+
+    headers.insert("content-md5", HeaderValue::from_static("potato"));
+    assert_eq!(
+        S3Error::InvalidDigest,
+        Expected::from_headers(&headers).expect_err("invalid")
+    );
+}
+
+#[test]
+fn compositing() {
+    let part = |crc32: &str| Checksums {
+        crc32: Some(crc32.to_string()),
+        ..Checksums::default()
+    };
+    let hello = part("NhCmhg==");
+    let world = part("OncRQw==");
+
+    let composite = Checksums::composite(&[&hello, &world]);
+    assert_eq!(Some("wpn7tg==-2".to_string()), composite.crc32);
+    assert_eq!(None, composite.sha256);
+
+    // a part uploaded without the checksum leaves nothing to combine
+    assert!(Checksums::composite(&[&hello, &Checksums::default()]).is_empty());
+}
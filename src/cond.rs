@@ -0,0 +1,153 @@
+use chrono::DateTime;
+use chrono::Utc;
+use hyper::HeaderMap;
+
+use super::dir::FileVersion;
+
+/// The `If-*` headers of a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Conditions {
+    if_match: Option<EntityTags>,
+    if_none_match: Option<EntityTags>,
+    if_modified_since: Option<DateTime<Utc>>,
+    if_unmodified_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum EntityTags {
+    Any,
+    Listed(Vec<String>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+impl Conditions {
+    pub fn from_headers(headers: &HeaderMap) -> Conditions {
+        Conditions::with_prefix(headers, "")
+    }
+
+    /// The `x-amz-copy-source-if-*` headers, which apply to the source of a copy.
+    pub fn from_copy_source_headers(headers: &HeaderMap) -> Conditions {
+        Conditions::with_prefix(headers, "x-amz-copy-source-")
+    }
+
+    fn with_prefix(headers: &HeaderMap, prefix: &str) -> Conditions {
+        let header = |name: &str| {
+            headers
+                .get(format!("{}{}", prefix, name).as_str())
+                .and_then(|v| v.to_str().ok())
+        };
+        Conditions {
+            if_match: header("if-match").map(EntityTags::parse),
+            if_none_match: header("if-none-match").map(EntityTags::parse),
+            if_modified_since: header("if-modified-since").and_then(http_date),
+            if_unmodified_since: header("if-unmodified-since").and_then(http_date),
+        }
+    }
+
+    /// Evaluate for a GET or HEAD of an existing version, in the order from RFC 7232.
+    pub fn check_read(&self, version: &FileVersion) -> Outcome {
+        let etag = match version.etag() {
+            Ok(etag) => etag,
+            Err(_) => return Outcome::PreconditionFailed,
+        };
+        // http dates only have second precision
+        let modified = version.modified().timestamp();
+
+        if let Some(tags) = &self.if_match {
+            if !tags.matches(&etag) {
+                return Outcome::PreconditionFailed;
+            }
+        } else if let Some(since) = self.if_unmodified_since {
+            if modified > since.timestamp() {
+                return Outcome::PreconditionFailed;
+            }
+        }
+
+        if let Some(tags) = &self.if_none_match {
+            if tags.matches(&etag) {
+                return Outcome::NotModified;
+            }
+        } else if let Some(since) = self.if_modified_since {
+            if modified <= since.timestamp() {
+                return Outcome::NotModified;
+            }
+        }
+
+        Outcome::Proceed
+    }
+
+    /// Evaluate for a write, given the current live version of the key, if there is one.
+    pub fn check_write(&self, current: Option<&FileVersion>) -> bool {
+        let etag = current.and_then(|version| version.etag().ok());
+
+        if let Some(tags) = &self.if_match {
+            match &etag {
+                Some(etag) if tags.matches(etag) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(tags) = &self.if_none_match {
+            if let Some(etag) = &etag {
+                if tags.matches(etag) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+impl EntityTags {
+    fn parse(header: &str) -> EntityTags {
+        if "*" == header.trim() {
+            return EntityTags::Any;
+        }
+        EntityTags::Listed(header.split(',').map(normalise).collect())
+    }
+
+    fn matches(&self, etag: &str) -> bool {
+        match self {
+            EntityTags::Any => true,
+            EntityTags::Listed(tags) => {
+                let etag = normalise(etag);
+                tags.contains(&etag)
+            }
+        }
+    }
+}
+
+/// Strip the weakness indicator and quotes; we only compare weakly.
+fn normalise(tag: &str) -> String {
+    let tag = tag.trim();
+    tag.trim_start_matches("W/").trim_matches('"').to_string()
+}
+
+fn http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+#[test]
+fn parsing() {
+    assert_eq!(EntityTags::Any, EntityTags::parse(" * "));
+    assert_eq!(
+        EntityTags::Listed(vec!["abc".to_string(), "def".to_string()]),
+        EntityTags::parse("\"abc\", W/\"def\"")
+    );
+    assert!(EntityTags::parse("\"abc\", \"def\"").matches("\"def\""));
+    assert!(!EntityTags::parse("\"abc\"").matches("\"def\""));
+
+    assert_eq!(
+        Some(1_577_836_800),
+        http_date("Wed, 01 Jan 2020 00:00:00 GMT").map(|d| d.timestamp())
+    );
+}
@@ -0,0 +1,127 @@
+use std::path::Path;
+
+use failure::Error;
+use serde_derive::Deserialize;
+use tokio::sync::Mutex;
+
+use super::bucket::VersioningPolicy;
+use super::dir;
+use super::xml::Xml;
+
+/// S3 refuses to delete more than this many keys in one request.
+const MAX_KEYS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Request {
+    #[serde(default)]
+    quiet: bool,
+    #[serde(rename = "Object", default)]
+    objects: Vec<ObjectIdentifier>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ObjectIdentifier {
+    key: String,
+    version_id: Option<String>,
+}
+
+enum Outcome {
+    Deleted,
+    Failed(&'static str, String),
+}
+
+impl Request {
+    /// Parse the body of a DeleteObjects request, if it's acceptable.
+    pub fn from_xml(body: &[u8]) -> Option<Request> {
+        let request: Request = serde_xml_rs::from_reader(body).ok()?;
+        if request.objects.is_empty() || request.objects.len() > MAX_KEYS {
+            return None;
+        }
+        Some(request)
+    }
+}
+
+/// Tombstone each requested key, or purge the requested versions, and render the DeleteResult.
+///
+/// Keys which aren't `allowed` are left alone, and reported as denied.
+pub async fn delete_objects(
+    store: &Path,
+    meta_lock: &Mutex<()>,
+    versioning: VersioningPolicy,
+    request: Request,
+    allowed: impl Fn(&str) -> bool,
+) -> Result<String, Error> {
+    let mut results = Vec::with_capacity(request.objects.len());
+    for object in &request.objects {
+        let outcome = if object.key.is_empty() {
+            Outcome::Failed("InvalidArgument", "empty key".to_string())
+        } else if !allowed(&object.key) {
+            Outcome::Failed("AccessDenied", "Access Denied".to_string())
+        } else if let Some(Err(_)) = object.version_id.as_ref().map(|id| id.parse::<usize>()) {
+            Outcome::Failed("NoSuchVersion", "invalid version id".to_string())
+        } else {
+            let version_id = object.version_id.as_ref().and_then(|id| id.parse().ok());
+            let deleted = match version_id {
+                // deleting a version which doesn't exist is a success
+                Some(id) => dir::purge(store, meta_lock, &object.key, id)
+                    .await
+                    .map(|_| ()),
+                None => dir::delete(store, meta_lock, &object.key, versioning).await,
+            };
+            match deleted {
+                Ok(()) => Outcome::Deleted,
+                Err(e) => {
+                    log::error!("deleting {:?} failed: {:?}", object.key, e);
+                    Outcome::Failed("InternalError", e.to_string())
+                }
+            }
+        };
+        results.push((object, outcome));
+    }
+
+    let mut xml = Xml::new("DeleteResult");
+    for (object, outcome) in results {
+        match outcome {
+            Outcome::Deleted if request.quiet => (),
+            Outcome::Deleted => {
+                xml.open("Deleted");
+                xml.text("Key", &object.key);
+                if let Some(id) = &object.version_id {
+                    xml.text("VersionId", id);
+                }
+                xml.close("Deleted");
+            }
+            Outcome::Failed(code, message) => {
+                xml.open("Error");
+                xml.text("Key", &object.key);
+                xml.text("Code", code);
+                xml.text("Message", message);
+                xml.close("Error");
+            }
+        }
+    }
+    Ok(xml.finish())
+}
+
+#[test]
+fn parse() {
+    let request = Request::from_xml(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+        <Delete xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+        <Object><Key>a &amp; b</Key></Object>
+        <Object><Key>c</Key><VersionId>3</VersionId></Object>
+        <Quiet>true</Quiet>
+        </Delete>"#,
+    )
+    .expect("valid");
+    assert!(request.quiet);
+    assert_eq!(2, request.objects.len());
+    assert_eq!("a & b", request.objects[0].key);
+    assert_eq!(None, request.objects[0].version_id);
+    assert_eq!(Some("3"), request.objects[1].version_id.as_deref());
+
+    assert!(Request::from_xml(b"<Delete></Delete>").is_none());
+    assert!(Request::from_xml(b"<Delete><Object>").is_none());
+}
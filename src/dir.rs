@@ -14,22 +14,158 @@ use tokio::fs;
 use tokio::io::AsyncWriteExt as _;
 use tokio::sync::Mutex;
 
+use crate::bucket::VersioningPolicy;
+use crate::checksum::Checksums;
 use crate::temp::TempPath;
 
-pub async fn get(root: &Path, key: &str) -> Result<Option<(FileMeta, fs::File)>, Error> {
-    let key = PackedKey::from(key);
-    let meta = match load_meta(root, &key).await? {
+/// A version of the key (by default, the live one), its id, and its data.
+pub async fn get(
+    root: &Path,
+    key: &str,
+    version: Option<usize>,
+) -> Result<Option<(usize, FileVersion, fs::File)>, Error> {
+    let packed = PackedKey::from(key);
+
+    // with versioning off, a write replaces the data under us, so look at the new meta
+    for _ in 0..2 {
+        let (id, found) = match head(root, key, version).await? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        match open_version(root, &packed, u64::try_from(found.data_id(id))?).await {
+            Ok(file) => return Ok(Some((id, found, file))),
+            Err(e) => match e.downcast_ref::<io::Error>() {
+                Some(e) if io::ErrorKind::NotFound == e.kind() => continue,
+                _ => return Err(e),
+            },
+        }
+    }
+
+    // the version has been removed since we read the meta, again
+    Ok(None)
+}
+
+/// A version of the key (by default, the live one), and its id,
+/// if it exists and isn't a tombstone.
+pub async fn head(
+    root: &Path,
+    key: &str,
+    version: Option<usize>,
+) -> Result<Option<(usize, FileVersion)>, Error> {
+    let meta = match load_meta(root, &PackedKey::from(key)).await? {
         Some(meta) => meta,
         None => return Ok(None),
     };
-    let file = open_version(root, &key, u64::try_from(meta.latest_version_id()?)?).await?;
-    Ok(Some((meta, file)))
+    let id = match version {
+        Some(id) => id,
+        None => meta.latest_version_id()?,
+    };
+    match meta.version(id) {
+        Some(version) if !version.tombstone => Ok(Some((id, version.clone()))),
+        _ => Ok(None),
+    }
+}
+
+/// Every key with metadata in the store, in no particular order.
+pub async fn list(root: &Path) -> Result<Vec<FileMeta>, Error> {
+    let mut found = Vec::new();
+    for path in meta_paths(root).await? {
+        if let Some(meta) = read_meta(&path).await? {
+            found.push(meta);
+        }
+    }
+    Ok(found)
+}
+
+/// Move objects stored before keys were namespaced by bucket into a bucket's store.
+///
+/// These keys still have the slash which separated them from the bucket name.
+/// This doesn't take the meta lock, so mustn't be run against a live server.
+pub async fn migrate(root: &Path, store: &Path) -> Result<usize, Error> {
+    let mut moved = 0;
+    for mut old in meta_paths(root).await? {
+        let mut meta = match read_meta(&old).await? {
+            Some(meta) => meta,
+            None => continue,
+        };
+
+        if meta.key.starts_with('/') {
+            meta.key.remove(0);
+        }
+
+        let mut new = PackedKey::from(meta.key.as_str()).as_path(store);
+        assert!(new.set_extension("meta"));
+        if fs::metadata(&new).await.is_ok() {
+            log::warn!("not migrating {:?}, {:?} already exists", meta.key, new);
+            continue;
+        }
+
+        fs::create_dir_all(new.parent().expect("structured path")).await?;
+
+        for (id, version) in meta.versions.iter().enumerate() {
+            let data = version.data_id(id);
+            assert!(old.set_extension(format!("{}", data)));
+            assert!(new.set_extension(format!("{}", data)));
+            match fs::rename(&old, &new).await {
+                Ok(()) => (),
+                Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+                Err(e) => Err(e)?,
+            }
+        }
+
+        assert!(new.set_extension("meta"));
+        write_meta(&new, &meta).await?;
+
+        assert!(old.set_extension("meta"));
+        fs::remove_file(&old).await?;
+
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// The paths of all the meta files in the store.
+async fn meta_paths(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut found = Vec::new();
+    for first in packed_dirs(root).await? {
+        for second in packed_dirs(&first).await? {
+            let mut entries = fs::read_dir(&second).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if Some("meta") == path.extension().and_then(|ext| ext.to_str()) {
+                    found.push(path);
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// The directories which look like they were created by `PackedKey::as_path`.
+async fn packed_dirs(parent: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut ret = Vec::new();
+    let mut entries = fs::read_dir(parent).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let plausible = name
+            .to_str()
+            .map(|name| 4 == name.len() && name.chars().all(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or(false);
+        if plausible && entry.file_type().await?.is_dir() {
+            ret.push(entry.path());
+        }
+    }
+    Ok(ret)
 }
 
 async fn load_meta(root: &Path, key: &PackedKey) -> Result<Option<FileMeta>, Error> {
     let mut root = key.as_path(root);
     assert!(root.set_extension("meta"));
-    match fs::read(&root).await {
+    read_meta(&root).await
+}
+
+async fn read_meta(path: &Path) -> Result<Option<FileMeta>, Error> {
+    match fs::read(path).await {
         Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
         Err(ref e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
         Err(e) => Err(e)?,
@@ -47,36 +183,49 @@ async fn write_new_version(
     mut root: PathBuf,
     meta: HashMap<String, String>,
     intermediate: Intermediate,
-) -> Result<(), Error> {
-    let mut data = match fs::read(&root).await {
-        Ok(data) => serde_json::from_slice(&data)?,
-        Err(ref e) if io::ErrorKind::NotFound == e.kind() => FileMeta {
+    versioning: VersioningPolicy,
+    precondition: impl FnOnce(Option<&FileVersion>) -> bool,
+) -> Result<Option<(usize, FileVersion)>, Error> {
+    assert!(root.set_extension("meta"));
+    let mut data = match read_meta(&root).await? {
+        Some(data) => data,
+        None => FileMeta {
             key: key.to_string(),
             versions: Vec::with_capacity(1),
+            next_data: 0,
         },
-        Err(e) => Err(e)?,
     };
 
-    let new_version = data.versions.len();
-
-    data.versions.push(FileVersion {
-        modified: Utc::now(),
-        content_length: intermediate.content.length,
-        content_md5_base64: intermediate.content.md5_base64,
-        meta,
-        tombstone: false,
-    });
+    let current = match data.latest_version_id() {
+        Ok(id) if !data.versions[id].tombstone => Some(&data.versions[id]),
+        _ => None,
+    };
 
-    let data = serde_json::to_vec(&data)?;
+    if !precondition(current) {
+        return Ok(None);
+    }
 
-    let mut meta_temp =
-        super::temp::NamedTempFile::new_in(root.parent().expect("structured dir")).await?;
-    meta_temp.write_all(&data).await?;
-    let meta_temp = meta_temp.into_temp_path();
+    let (new_version, obsolete) = data.place(
+        FileVersion {
+            modified: Utc::now(),
+            content_length: intermediate.content.length,
+            content_md5_base64: intermediate.content.md5_base64,
+            meta,
+            tombstone: false,
+            frames: intermediate.content.frames,
+            parts: intermediate.content.parts,
+            checksums: intermediate.content.checksums,
+            unversioned: false,
+            purged: false,
+            data: None,
+        },
+        versioning,
+    );
 
-    // ensure the data exists before we write the metadata
-    // this will clobber existing versions if they wrote before a crash before?
-    assert!(root.set_extension(format!("{}", new_version)));
+    // the data goes in a fresh file, so can be in place before the metadata refers to it,
+    // without disturbing anything a reader might have found from the old metadata
+    let data_id = data.versions[new_version].data_id(new_version);
+    assert!(root.set_extension(format!("{}", data_id)));
     intermediate
         .temp
         .persist(&root)
@@ -84,35 +233,334 @@ async fn write_new_version(
         .map_err(|e| e.error)?;
 
     assert!(root.set_extension("meta"));
-    meta_temp.persist(&root).await.map_err(|e| e.error)?;
+    write_meta(&root, &data).await?;
+
+    remove_data(&root, obsolete).await?;
 
     log::debug!("wrote {:?}", root);
 
+    let version = data.versions[new_version].clone();
+    Ok(Some((new_version, version)))
+}
+
+/// Remove the data files with these ids, for a key, which may not exist.
+async fn remove_data(path: &Path, ids: impl IntoIterator<Item = usize>) -> Result<(), Error> {
+    let mut path = path.to_path_buf();
+    for id in ids {
+        assert!(path.set_extension(format!("{}", id)));
+        match fs::remove_file(&path).await {
+            Ok(()) => (),
+            Err(ref e) if io::ErrorKind::NotFound == e.kind() => (),
+            Err(e) => Err(e)?,
+        }
+    }
+    Ok(())
+}
+
+async fn write_meta(path: &Path, meta: &FileMeta) -> Result<(), Error> {
+    let data = serde_json::to_vec(meta)?;
+
+    let mut meta_temp =
+        super::temp::NamedTempFile::new_in(path.parent().expect("structured dir")).await?;
+    meta_temp.write_all(&data).await?;
+    meta_temp
+        .into_temp_path()
+        .persist(path)
+        .await
+        .map_err(|e| e.error)?;
     Ok(())
 }
 
+/// Add a new version of the key, if the `precondition` accepts the current (live) version.
+///
+/// Returns the id of the new version, and the version itself, if it was written.
 pub async fn store(
     root: &Path,
     meta_lock: &Mutex<()>,
     key: &str,
     meta: HashMap<String, String>,
     intermediate: Intermediate,
-) -> Result<(), Error> {
+    versioning: VersioningPolicy,
+    precondition: impl FnOnce(Option<&FileVersion>) -> bool,
+) -> Result<Option<(usize, FileVersion)>, Error> {
     let root = PackedKey::from(key).as_path(root);
 
     fs::create_dir_all(root.parent().expect("structured path")).await?;
 
-    {
+    let _writing = meta_lock.lock().await;
+    write_new_version(key, root, meta, intermediate, versioning, precondition).await
+}
+
+/// Link a version of the key (by default, the live one) into a temporary file in `into`,
+/// so it can be stored under another key without being unpacked.
+///
+/// Returns the version's details alongside, or `None` if there's no such version.
+pub async fn link_version(
+    root: &Path,
+    key: &str,
+    version: Option<usize>,
+    into: &Path,
+) -> Result<Option<(FileVersion, Intermediate)>, Error> {
+    let packed = PackedKey::from(key);
+    let data = match load_meta(root, &packed).await? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    let id = match version {
+        Some(id) => id,
+        None => data.latest_version_id()?,
+    };
+
+    let version = match data.version(id) {
+        Some(version) if !version.tombstone => version,
+        _ => return Ok(None),
+    };
+
+    let mut path = packed.as_path(root);
+    assert!(path.set_extension(format!("{}", version.data_id(id))));
+    let temp = match TempPath::hard_link_in(&path, into).await {
+        Ok(temp) => temp,
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            // the version has been removed since we read the meta
+            Some(e) if io::ErrorKind::NotFound == e.kind() => return Ok(None),
+            _ => return Err(e),
+        },
+    };
+
+    let content = ContentInfo {
+        length: version.content_length,
+        md5_base64: version.content_md5_base64.to_string(),
+        frames: version.frames.clone(),
+        parts: version.parts,
+        checksums: version.checksums.clone(),
+    };
+
+    Ok(Some((version.clone(), Intermediate { temp, content })))
+}
+
+/// Mark the key as deleted, by adding a tombstone version, if it exists and isn't already.
+pub async fn delete(
+    root: &Path,
+    meta_lock: &Mutex<()>,
+    key: &str,
+    versioning: VersioningPolicy,
+) -> Result<(), Error> {
+    delete_if(root, meta_lock, key, versioning, |_| true).await?;
+    Ok(())
+}
+
+/// Mark the key as deleted, if it exists, and the `precondition` accepts the live version.
+///
+/// Returns whether the key was deleted.
+pub async fn delete_if(
+    root: &Path,
+    meta_lock: &Mutex<()>,
+    key: &str,
+    versioning: VersioningPolicy,
+    precondition: impl FnOnce(&FileVersion) -> bool,
+) -> Result<bool, Error> {
+    let mut root = PackedKey::from(key).as_path(root);
+    assert!(root.set_extension("meta"));
+
+    let _writing = meta_lock.lock().await;
+
+    let mut data = match read_meta(&root).await? {
+        Some(data) => data,
+        None => return Ok(false),
+    };
+
+    if data.deleted()? || !precondition(data.latest_version()?) {
+        return Ok(false);
+    }
+
+    let (_, obsolete) = data.place(
+        FileVersion {
+            modified: Utc::now(),
+            content_length: 0,
+            content_md5_base64: EMPTY_MD5_BASE64.to_string(),
+            meta: HashMap::new(),
+            tombstone: true,
+            frames: FrameIndex::default(),
+            parts: None,
+            checksums: Checksums::default(),
+            unversioned: false,
+            purged: false,
+            data: None,
+        },
+        versioning,
+    );
+
+    write_meta(&root, &data).await?;
+
+    remove_data(&root, obsolete).await?;
+
+    log::debug!("deleted {:?}", root);
+
+    Ok(true)
+}
+
+/// Permanently remove a version of the key, returning whether it existed.
+///
+/// Other versions keep their ids. If no versions remain, the key is gone entirely.
+pub async fn purge(
+    root: &Path,
+    meta_lock: &Mutex<()>,
+    key: &str,
+    id: usize,
+) -> Result<bool, Error> {
+    let mut root = PackedKey::from(key).as_path(root);
+    assert!(root.set_extension("meta"));
+
+    let _writing = meta_lock.lock().await;
+
+    let mut data = match read_meta(&root).await? {
+        Some(data) => data,
+        None => return Ok(false),
+    };
+
+    let data_id = match data.versions.get_mut(id) {
+        Some(version) if !version.purged => {
+            version.purge();
+            version.data_id(id)
+        }
+        _ => return Ok(false),
+    };
+
+    write_or_remove_meta(&root, &data).await?;
+
+    remove_data(&root, vec![data_id]).await?;
+
+    log::debug!("purged {} of {:?}", id, root);
+
+    Ok(true)
+}
+
+/// Purge the versions of each key which `select` picks, e.g. `FileMeta::superseded`.
+///
+/// Returns how many versions were removed.
+pub async fn collect(
+    root: &Path,
+    meta_lock: &Mutex<()>,
+    select: impl Fn(&FileMeta) -> Result<Vec<usize>, Error>,
+) -> Result<usize, Error> {
+    let mut removed = 0;
+    for path in meta_paths(root).await? {
         let _writing = meta_lock.lock().await;
-        write_new_version(key, root, meta, intermediate).await?;
+
+        let mut data = match read_meta(&path).await? {
+            Some(data) => data,
+            None => continue,
+        };
+
+        let obsolete = select(&data)?;
+        if obsolete.is_empty() {
+            continue;
+        }
+
+        let mut files = Vec::with_capacity(obsolete.len());
+        for id in &obsolete {
+            let version = &mut data.versions[*id];
+            version.purge();
+            files.push(version.data_id(*id));
+        }
+
+        write_or_remove_meta(&path, &data).await?;
+
+        removed += obsolete.len();
+        remove_data(&path, files).await?;
     }
+    Ok(removed)
+}
 
+/// Write the metadata, or remove it if every version has been purged.
+async fn write_or_remove_meta(path: &Path, meta: &FileMeta) -> Result<(), Error> {
+    if meta.versions.iter().all(|version| version.purged) {
+        fs::remove_file(path).await?;
+    } else {
+        write_meta(path, meta).await?;
+    }
     Ok(())
 }
 
+const EMPTY_MD5_BASE64: &str = "1B2M2Y8AsgTpgAmY7PhCfg==";
+
+#[derive(Debug)]
 pub struct ContentInfo {
     pub length: u64,
+    /// For multipart uploads, the md5 of the parts' md5s.
     pub md5_base64: String,
+    pub frames: FrameIndex,
+    /// How many parts a multipart upload was assembled from.
+    pub parts: Option<u64>,
+    pub checksums: Checksums,
+}
+
+impl ContentInfo {
+    pub fn etag(&self) -> Result<String, Error> {
+        etag(&self.md5_base64, self.parts)
+    }
+}
+
+fn etag(md5_base64: &str, parts: Option<u64>) -> Result<String, Error> {
+    let md5 = hex::encode(base64::decode(md5_base64)?);
+    Ok(match parts {
+        Some(parts) => format!("\"{}-{}\"", md5, parts),
+        None => format!("\"{}\"", md5),
+    })
+}
+
+/// Where the independent zstd frames of a packed file start, so we can seek.
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub struct FrameIndex {
+    /// The uncompressed length of every frame except the last.
+    pub frame_size: u64,
+    /// The offset of the start of each frame in the packed file.
+    pub offsets: Vec<u64>,
+    /// The uncompressed position of the start of each frame, if they're not all `frame_size`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub starts: Vec<u64>,
+}
+
+impl FrameIndex {
+    /// The offset in the packed file to start decompressing from, to reach an uncompressed
+    /// `position`, and how much uncompressed data will need discarding to get there.
+    pub fn seek_for(&self, position: u64) -> (u64, u64) {
+        if !self.starts.is_empty() {
+            let frame = match self.starts.binary_search(&position) {
+                Ok(frame) => frame,
+                Err(after) => after - 1,
+            };
+            return (self.offsets[frame], position - self.starts[frame]);
+        }
+
+        // data stored before frames were recorded is a single frame
+        if 0 == self.frame_size || self.offsets.is_empty() {
+            return (0, position);
+        }
+
+        let frame = usize::try_from(position / self.frame_size)
+            .unwrap_or(usize::MAX)
+            .min(self.offsets.len() - 1);
+
+        let frame_start = self.frame_size * frame as u64;
+        (self.offsets[frame], position - frame_start)
+    }
+
+    /// Add the frames of another packed file, which has been appended at `offset`,
+    /// and whose data starts at the uncompressed `position`.
+    pub fn append(&mut self, other: &FrameIndex, position: u64, offset: u64) {
+        for (frame, other_offset) in other.offsets.iter().enumerate() {
+            let other_start = match other.starts.get(frame) {
+                Some(start) => *start,
+                None => other.frame_size * frame as u64,
+            };
+            self.offsets.push(offset + other_offset);
+            self.starts.push(position + other_start);
+        }
+    }
 }
 
 pub struct Intermediate {
@@ -150,31 +598,382 @@ impl PackedKey {
 pub struct FileMeta {
     key: String,
     versions: Vec<FileVersion>,
+    /// The data id for the next write; they're never reused, so a write can't replace data
+    /// which a reader found from older metadata.
+    #[serde(default)]
+    next_data: usize,
 }
 
 impl FileMeta {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
     pub fn deleted(&self) -> Result<bool, Error> {
         Ok(self.latest_version()?.tombstone)
     }
 
+    /// Whether the key has been deleted and there's no history behind the delete markers.
+    pub fn only_tombstones(&self) -> bool {
+        self.versions().all(|(_, version)| version.tombstone)
+    }
+
+    /// The id of the newest version which hasn't been purged.
     pub fn latest_version_id(&self) -> Result<usize, Error> {
-        Ok(self
-            .versions
-            .len()
-            .checked_sub(1)
-            .ok_or_else(|| err_msg("versions array cannot be empty"))?)
+        self.versions
+            .iter()
+            .rposition(|version| !version.purged)
+            .ok_or_else(|| err_msg("versions array cannot be empty"))
     }
 
     pub fn latest_version(&self) -> Result<&FileVersion, Error> {
         Ok(&self.versions[self.latest_version_id()?])
     }
+
+    /// The version with this id, unless it has been purged.
+    pub fn version(&self, id: usize) -> Option<&FileVersion> {
+        self.versions.get(id).filter(|version| !version.purged)
+    }
+
+    /// The versions which haven't been purged, and their ids, newest first.
+    pub fn versions(&self) -> impl Iterator<Item = (usize, &FileVersion)> {
+        self.versions
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, version)| !version.purged)
+    }
+
+    /// The ids of the versions which aren't live: everything, if the key has been deleted.
+    pub fn superseded(&self) -> Result<Vec<usize>, Error> {
+        let latest = self.latest_version_id()?;
+        let deleted = self.versions[latest].tombstone;
+        Ok(self
+            .versions()
+            .map(|(id, _)| id)
+            .filter(|id| deleted || latest != *id)
+            .collect())
+    }
+
+    /// The ids of the versions which were replaced by a newer version (or tombstone)
+    /// before `cutoff`.
+    pub fn noncurrent_before(&self, cutoff: DateTime<Utc>) -> Vec<usize> {
+        let mut ret = Vec::new();
+        let mut replaced = None;
+        for (id, version) in self.versions() {
+            match replaced {
+                Some(replaced) if replaced < cutoff => ret.push(id),
+                _ => (),
+            }
+            replaced = Some(version.modified);
+        }
+        ret
+    }
+
+    /// Add a version to the history, as the `versioning` policy dictates.
+    ///
+    /// Returns its id, and the data ids of any versions whose data is no longer needed.
+    fn place(
+        &mut self,
+        mut version: FileVersion,
+        versioning: VersioningPolicy,
+    ) -> (usize, Vec<usize>) {
+        version.unversioned = VersioningPolicy::On != versioning;
+        version.data = Some(self.fresh_data_id());
+
+        match versioning {
+            VersioningPolicy::On => {
+                self.versions.push(version);
+                (self.versions.len() - 1, Vec::new())
+            }
+
+            // there's only ever one version, which is replaced in place
+            VersioningPolicy::Off => {
+                let obsolete = self
+                    .versions()
+                    .map(|(id, version)| version.data_id(id))
+                    .collect();
+                self.versions.clear();
+                self.versions.push(version);
+                (0, obsolete)
+            }
+
+            // the existing history is kept, but there's only one unversioned version,
+            // which is replaced in place if it's the latest
+            VersioningPolicy::Suspended => {
+                let mut unversioned: Vec<usize> = self
+                    .versions()
+                    .filter(|(_, version)| version.unversioned)
+                    .map(|(id, _)| id)
+                    .collect();
+
+                let obsolete = unversioned
+                    .iter()
+                    .map(|id| self.versions[*id].data_id(*id))
+                    .collect();
+
+                let last = self.versions.len().checked_sub(1);
+                let id = match last {
+                    Some(last) if unversioned.contains(&last) => {
+                        unversioned.retain(|id| *id != last);
+                        self.versions[last] = version;
+                        last
+                    }
+                    _ => {
+                        self.versions.push(version);
+                        self.versions.len() - 1
+                    }
+                };
+
+                for id in &unversioned {
+                    self.versions[*id].purge();
+                }
+
+                (id, obsolete)
+            }
+        }
+    }
+
+    fn fresh_data_id(&mut self) -> usize {
+        // data used to be named after the version ids, which may be past the counter
+        let id = self.next_data.max(self.versions.len());
+        self.next_data = id + 1;
+        id
+    }
 }
 
-#[derive(serde_derive::Serialize, serde_derive::Deserialize)]
+#[derive(Clone, serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct FileVersion {
     modified: DateTime<Utc>,
     content_length: u64,
     content_md5_base64: String,
     meta: HashMap<String, String>,
     tombstone: bool,
+    #[serde(default)]
+    frames: FrameIndex,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parts: Option<u64>,
+    #[serde(default, skip_serializing_if = "Checksums::is_empty")]
+    checksums: Checksums,
+    /// Written while versioning was off or suspended, so will be replaced by the next write.
+    #[serde(default)]
+    unversioned: bool,
+    /// Permanently deleted; kept as a placeholder so later versions keep their ids.
+    #[serde(default)]
+    purged: bool,
+    /// The id of the file holding the data, if it isn't the version's own id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<usize>,
+}
+
+impl FileVersion {
+    /// The id of the file holding the data, for the version with this `id`.
+    fn data_id(&self, id: usize) -> usize {
+        self.data.unwrap_or(id)
+    }
+
+    fn purge(&mut self) {
+        self.purged = true;
+        self.meta.clear();
+        self.frames = FrameIndex::default();
+        self.checksums = Checksums::default();
+    }
+
+    pub fn tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    pub fn modified(&self) -> DateTime<Utc> {
+        self.modified
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.content_length
+    }
+
+    pub fn frames(&self) -> &FrameIndex {
+        &self.frames
+    }
+
+    /// How many parts a multipart upload was assembled from.
+    pub fn parts(&self) -> Option<u64> {
+        self.parts
+    }
+
+    /// The additional checksums the uploader asked for.
+    pub fn checksums(&self) -> &Checksums {
+        &self.checksums
+    }
+
+    /// The (signed) headers the version was uploaded with.
+    pub fn meta(&self) -> &HashMap<String, String> {
+        &self.meta
+    }
+
+    /// The quoted, hex md5, as S3 clients expect to see it,
+    /// with the part count for multipart uploads.
+    pub fn etag(&self) -> Result<String, Error> {
+        etag(&self.content_md5_base64, self.parts)
+    }
+}
+
+#[test]
+fn seeking() {
+    let legacy = FrameIndex::default();
+    assert_eq!((0, 12), legacy.seek_for(12));
+
+    let frames = FrameIndex {
+        frame_size: 10,
+        offsets: vec![0, 4, 9],
+        starts: Vec::new(),
+    };
+    assert_eq!((0, 0), frames.seek_for(0));
+    assert_eq!((0, 9), frames.seek_for(9));
+    assert_eq!((4, 0), frames.seek_for(10));
+    assert_eq!((9, 5), frames.seek_for(25));
+    assert_eq!((9, 15), frames.seek_for(35));
+
+    // two parts, of 25 and 5 bytes, packed into 12 and 3 bytes
+    let mut joined = FrameIndex::default();
+    joined.append(&frames, 0, 0);
+    joined.append(
+        &FrameIndex {
+            frame_size: 10,
+            offsets: vec![0],
+            starts: Vec::new(),
+        },
+        25,
+        12,
+    );
+    assert_eq!(vec![0, 4, 9, 12], joined.offsets);
+    assert_eq!(vec![0, 10, 20, 25], joined.starts);
+    assert_eq!((0, 9), joined.seek_for(9));
+    assert_eq!((9, 4), joined.seek_for(24));
+    assert_eq!((12, 0), joined.seek_for(25));
+    assert_eq!((12, 4), joined.seek_for(29));
+}
+
+#[test]
+fn placing() {
+    let version = |tombstone| FileVersion {
+        modified: Utc::now(),
+        content_length: 0,
+        content_md5_base64: EMPTY_MD5_BASE64.to_string(),
+        meta: HashMap::new(),
+        tombstone,
+        frames: FrameIndex::default(),
+        parts: None,
+        checksums: Checksums::default(),
+        unversioned: false,
+        purged: false,
+        data: None,
+    };
+
+    let mut meta = FileMeta {
+        key: "a".to_string(),
+        versions: Vec::new(),
+        next_data: 0,
+    };
+
+    assert_eq!(
+        (0, vec![]),
+        meta.place(version(false), VersioningPolicy::Off)
+    );
+    // the same version id, but the data goes somewhere new
+    assert_eq!(
+        (0, vec![0]),
+        meta.place(version(false), VersioningPolicy::Off)
+    );
+    assert_eq!(1, meta.versions.len());
+    assert_eq!(1, meta.versions[0].data_id(0));
+
+    assert_eq!(
+        (1, vec![]),
+        meta.place(version(false), VersioningPolicy::On)
+    );
+    assert_eq!((2, vec![]), meta.place(version(true), VersioningPolicy::On));
+
+    // the unversioned 0 is replaced by the new unversioned version, which is then replaced
+    assert_eq!(
+        (3, vec![1]),
+        meta.place(version(false), VersioningPolicy::Suspended)
+    );
+    assert_eq!(
+        (3, vec![4]),
+        meta.place(version(false), VersioningPolicy::Suspended)
+    );
+    assert_eq!(
+        vec![3, 2, 1],
+        meta.versions().map(|(id, _)| id).collect::<Vec<_>>()
+    );
+    assert_eq!(3, meta.latest_version_id().expect("present"));
+    assert!(meta.version(0).is_none());
+    assert_eq!(5, meta.versions[3].data_id(3));
+
+    assert_eq!(
+        (0, vec![5, 3, 2]),
+        meta.place(version(false), VersioningPolicy::Off)
+    );
+    assert_eq!(1, meta.versions.len());
+
+    // data from before writes had their own ids is named after the version
+    let mut legacy = FileMeta {
+        key: "a".to_string(),
+        versions: vec![version(false), version(false)],
+        next_data: 0,
+    };
+    assert_eq!(
+        (0, vec![1, 0]),
+        legacy.place(version(false), VersioningPolicy::Off)
+    );
+    assert_eq!(2, legacy.versions[0].data_id(0));
+}
+
+#[test]
+fn superseding() {
+    let version = |tombstone, purged| FileVersion {
+        modified: Utc::now(),
+        content_length: 0,
+        content_md5_base64: EMPTY_MD5_BASE64.to_string(),
+        meta: HashMap::new(),
+        tombstone,
+        frames: FrameIndex::default(),
+        parts: None,
+        checksums: Checksums::default(),
+        unversioned: false,
+        purged,
+        data: None,
+    };
+
+    let mut meta = FileMeta {
+        key: "a".to_string(),
+        versions: vec![
+            version(false, false),
+            version(false, true),
+            version(false, false),
+        ],
+        next_data: 0,
+    };
+    assert_eq!(vec![0], meta.superseded().expect("valid"));
+
+    meta.versions.push(version(true, false));
+    assert_eq!(vec![3, 2, 0], meta.superseded().expect("valid"));
+    assert!(!meta.only_tombstones());
+
+    // 0 was replaced by 2 a day ago, and 2 was replaced by the tombstone just now
+    let now = Utc::now();
+    meta.versions[2].modified = now - chrono::Duration::days(1);
+    meta.versions[3].modified = now;
+    assert_eq!(
+        vec![0],
+        meta.noncurrent_before(now - chrono::Duration::hours(1))
+    );
+    assert_eq!(
+        vec![2, 0],
+        meta.noncurrent_before(now + chrono::Duration::hours(1))
+    );
+
+    // deleting with versioning off leaves just the delete marker
+    meta.versions = vec![version(false, true), version(true, false)];
+    assert!(meta.only_tombstones());
 }
@@ -0,0 +1,214 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use failure::Error;
+use tokio::io::AsyncWriteExt as _;
+
+use super::xml::Xml;
+
+/// The reasons we refuse a request; the variant names are S3's error codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum S3Error {
+    AccessDenied,
+    AuthorizationHeaderMalformed,
+    BadDigest,
+    BucketNotEmpty,
+    EntityTooLarge,
+    EntityTooSmall,
+    IncompleteBody,
+    InternalError,
+    InvalidAccessKeyId,
+    InvalidArgument,
+    InvalidBucketName,
+    InvalidDigest,
+    InvalidLocationConstraint,
+    InvalidPart,
+    InvalidPartOrder,
+    InvalidPolicyDocument,
+    InvalidRange,
+    InvalidRequest,
+    MalformedXML,
+    MaxMessageLengthExceeded,
+    MaxPostPreDataLengthExceededError,
+    MethodNotAllowed,
+    NoSuchBucket,
+    NoSuchKey,
+    NoSuchLifecycleConfiguration,
+    NoSuchUpload,
+    NoSuchVersion,
+    NotImplemented,
+    PreconditionFailed,
+    SignatureDoesNotMatch,
+    XAmzContentSHA256Mismatch,
+}
+
+impl S3Error {
+    pub fn status(self) -> u16 {
+        use S3Error::*;
+        match self {
+            AccessDenied | InvalidAccessKeyId | SignatureDoesNotMatch => 403,
+            NoSuchBucket
+            | NoSuchKey
+            | NoSuchLifecycleConfiguration
+            | NoSuchUpload
+            | NoSuchVersion => 404,
+            MethodNotAllowed => 405,
+            BucketNotEmpty => 409,
+            PreconditionFailed => 412,
+            InvalidRange => 416,
+            InternalError => 500,
+            NotImplemented => 501,
+            AuthorizationHeaderMalformed
+            | BadDigest
+            | EntityTooLarge
+            | EntityTooSmall
+            | IncompleteBody
+            | InvalidArgument
+            | InvalidBucketName
+            | InvalidDigest
+            | InvalidLocationConstraint
+            | InvalidPart
+            | InvalidPartOrder
+            | InvalidPolicyDocument
+            | InvalidRequest
+            | MalformedXML
+            | MaxMessageLengthExceeded
+            | MaxPostPreDataLengthExceededError
+            | XAmzContentSHA256Mismatch => 400,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        use S3Error::*;
+        match self {
+            AccessDenied => "Access Denied",
+            AuthorizationHeaderMalformed => {
+                "The authorization header is malformed; the region is wrong."
+            }
+            BadDigest => "The digest you specified did not match what we received.",
+            BucketNotEmpty => "The bucket you tried to delete is not empty",
+            EntityTooLarge => "Your proposed upload exceeds the maximum allowed size",
+            EntityTooSmall => "Your proposed upload is smaller than the minimum allowed size",
+            IncompleteBody => "You did not provide the number of bytes specified by the headers.",
+            InternalError => "We encountered an internal error. Please try again.",
+            InvalidAccessKeyId => {
+                "The AWS access key Id you provided does not exist in our records."
+            }
+            InvalidArgument => "Invalid Argument",
+            InvalidBucketName => "The specified bucket is not valid.",
+            InvalidDigest => "The Content-MD5 you specified is not valid.",
+            InvalidLocationConstraint => "The specified location constraint is not valid.",
+            InvalidPart => "One or more of the specified parts could not be found.",
+            InvalidPartOrder => "The list of parts was not in ascending order.",
+            InvalidPolicyDocument => {
+                "The content of the form does not meet the conditions specified in the policy document."
+            }
+            InvalidRange => "The requested range is not satisfiable",
+            InvalidRequest => "Invalid Request",
+            MalformedXML => "The XML you provided was not well-formed or did not validate.",
+            MaxMessageLengthExceeded => "Your request was too big.",
+            MaxPostPreDataLengthExceededError => {
+                "Your POST request fields preceding the upload file were too large."
+            }
+            MethodNotAllowed => "The specified method is not allowed against this resource.",
+            NoSuchBucket => "The specified bucket does not exist",
+            NoSuchKey => "The specified key does not exist.",
+            NoSuchLifecycleConfiguration => "The lifecycle configuration does not exist",
+            NoSuchUpload => "The specified upload does not exist.",
+            NoSuchVersion => "The specified version does not exist.",
+            NotImplemented => "A header you provided implies functionality that is not implemented",
+            PreconditionFailed => "At least one of the preconditions you specified did not hold",
+            SignatureDoesNotMatch => {
+                "The request signature we calculated does not match the signature you provided."
+            }
+            XAmzContentSHA256Mismatch => {
+                "The provided 'x-amz-content-sha256' header does not match what was computed."
+            }
+        }
+    }
+
+    /// Render the `<Error>` document describing this refusal of a request for `resource`.
+    pub fn to_xml(self, resource: &str, request_id: &str) -> String {
+        let mut xml = Xml::new("Error");
+        xml.text("Code", format!("{:?}", self));
+        xml.text("Message", self.message());
+        xml.text("Resource", resource);
+        xml.text("RequestId", request_id);
+        xml.finish()
+    }
+}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self, self.message())
+    }
+}
+
+impl std::error::Error for S3Error {}
+
+/// Whether the error means we can't usefully keep serving, i.e. the `storage` root has become
+/// unwritable, as opposed to just this request failing.
+///
+/// Only errors which look like that are checked, by writing a probe file; e.g. one key's
+/// files having the wrong permissions doesn't stop us serving the others.
+pub async fn fatal(e: &Error, storage: &Path) -> bool {
+    let suspicious = e
+        .iter_chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem
+            )
+        });
+
+    suspicious && !writable(storage).await
+}
+
+async fn writable(storage: &Path) -> bool {
+    let probe = async {
+        let mut temp = super::temp::NamedTempFile::new_in(storage).await?;
+        temp.write_all(b"probe").await?;
+        temp.flush().await?;
+        Ok::<_, Error>(())
+    };
+
+    match probe.await {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("storage {:?} is unwritable: {:?}", storage, e);
+            false
+        }
+    }
+}
+
+#[test]
+fn rendering() {
+    let xml = S3Error::NoSuchKey.to_xml("/potato/a&b", "4442587FB7D0A2F9");
+    assert_eq!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Error xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         <Code>NoSuchKey</Code><Message>The specified key does not exist.</Message>\
+         <Resource>/potato/a&amp;b</Resource><RequestId>4442587FB7D0A2F9</RequestId></Error>",
+        xml
+    );
+    assert_eq!(404, S3Error::NoSuchKey.status());
+}
+
+#[tokio::test]
+async fn fatality() {
+    let denied = || io::Error::new(io::ErrorKind::PermissionDenied, "storage").into();
+    assert!(fatal(&denied(), Path::new("/nonexistent/swisher")).await);
+    assert!(!fatal(&denied(), &std::env::temp_dir()).await);
+
+    let missing = io::Error::new(io::ErrorKind::NotFound, "key");
+    assert!(!fatal(&missing.into(), Path::new("/nonexistent/swisher")).await);
+    assert!(
+        !fatal(
+            &S3Error::NoSuchKey.into(),
+            Path::new("/nonexistent/swisher")
+        )
+        .await
+    );
+}
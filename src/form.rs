@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+
+use failure::Error;
+use hyper::body::HttpBody as _;
+use hyper::body::Sender;
+
+use super::error::S3Error;
+
+/// The fields before the file, including their part headers; S3 allows 20kB.
+const MAX_FIELDS: usize = 20 * 1024;
+
+/// Decodes a `multipart/form-data` body, as it arrives.
+///
+/// The fields before the `file` are collected, then the file's data is returned as it's found.
+/// As in S3, anything after the file is ignored.
+pub struct Decoder {
+    /// `\r\n--<boundary>`, which precedes each part.
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+    fields: HashMap<String, String>,
+    /// How much of the fields we've read, including their headers.
+    read: usize,
+    /// Set once we've reached the file.
+    filename: Option<String>,
+}
+
+enum State {
+    Preamble,
+    /// Just after a delimiter, which is followed by either `\r\n`, or `--` for the end.
+    Delimited,
+    Headers,
+    Value(String),
+    File,
+    Done,
+}
+
+impl Decoder {
+    /// For a body with this `content-type`, if it's `multipart/form-data` with a boundary.
+    pub fn new(content_type: &str) -> Option<Decoder> {
+        let mut params = content_type.split(';').map(|param| param.trim());
+        if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+
+        let boundary = params
+            .filter_map(|param| {
+                let eq = param.find('=')?;
+                if param[..eq].trim().eq_ignore_ascii_case("boundary") {
+                    Some(param[eq + 1..].trim().trim_matches('"'))
+                } else {
+                    None
+                }
+            })
+            .next()?;
+
+        if boundary.is_empty() || boundary.len() > 70 {
+            return None;
+        }
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Some(Decoder {
+            delimiter,
+            // so the first delimiter looks like all the others
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            fields: HashMap::new(),
+            read: 0,
+            filename: None,
+        })
+    }
+
+    /// Feed in more of the body, returning whatever of the file's data it completes.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>, S3Error> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        while self.step(&mut out)? {}
+        Ok(out)
+    }
+
+    /// The fields before the file, with lower-case names, once the file has been reached.
+    pub fn fields(&self) -> Option<&HashMap<String, String>> {
+        match self.filename {
+            Some(_) => Some(&self.fields),
+            None => None,
+        }
+    }
+
+    /// The name the browser gave the file, which may be empty.
+    pub fn filename(&self) -> &str {
+        self.filename.as_deref().unwrap_or("")
+    }
+
+    /// The body has ended, which must be after the file.
+    pub fn finish(self) -> Result<(), S3Error> {
+        match (self.state, self.filename) {
+            (State::Done, Some(_)) => Ok(()),
+            (State::File, _) => Err(S3Error::IncompleteBody),
+            // S3 insists on exactly one file
+            _ => Err(S3Error::InvalidArgument),
+        }
+    }
+
+    /// Consume what we can from the buffer, returning whether it's worth trying again.
+    fn step(&mut self, out: &mut Vec<u8>) -> Result<bool, S3Error> {
+        match mem::replace(&mut self.state, State::Done) {
+            State::Preamble => match self.find(&self.delimiter) {
+                Some(start) => {
+                    self.buf.drain(..start + self.delimiter.len());
+                    self.state = State::Delimited;
+                    Ok(true)
+                }
+                None => {
+                    // the preamble is ignored, but might end with part of the delimiter
+                    let keep = self.buf.len().saturating_sub(self.delimiter.len());
+                    self.buf.drain(..keep);
+                    self.state = State::Preamble;
+                    Ok(false)
+                }
+            },
+            State::Delimited => {
+                if self.buf.len() < 2 {
+                    self.state = State::Delimited;
+                    return Ok(false);
+                }
+
+                if self.buf.starts_with(b"--") {
+                    return Ok(true);
+                }
+
+                if !self.buf.starts_with(b"\r\n") {
+                    return Err(S3Error::InvalidArgument);
+                }
+                self.buf.drain(..2);
+                self.state = State::Headers;
+                Ok(true)
+            }
+            State::Headers => {
+                // the headers end with an empty line, which may be the only line
+                let end = if self.buf.starts_with(b"\r\n") {
+                    Some(0)
+                } else {
+                    self.find(b"\r\n\r\n").map(|end| end + 2)
+                };
+
+                let end = match end {
+                    Some(end) => end,
+                    None => {
+                        self.check_size(self.buf.len())?;
+                        self.state = State::Headers;
+                        return Ok(false);
+                    }
+                };
+
+                self.check_size(end + 2)?;
+                self.read += end + 2;
+                let headers = String::from_utf8(self.buf[..end].to_vec())
+                    .map_err(|_| S3Error::InvalidArgument)?;
+                self.buf.drain(..end + 2);
+
+                let (name, filename) = disposition(&headers).ok_or(S3Error::InvalidArgument)?;
+                if "file" == name {
+                    self.filename = Some(filename.unwrap_or_default());
+                    self.state = State::File;
+                } else {
+                    self.state = State::Value(name);
+                }
+                Ok(true)
+            }
+            State::Value(name) => {
+                let end = match self.find(&self.delimiter) {
+                    Some(end) => end,
+                    None => {
+                        self.check_size(self.buf.len())?;
+                        self.state = State::Value(name);
+                        return Ok(false);
+                    }
+                };
+
+                self.check_size(end)?;
+                self.read += end;
+                let value = String::from_utf8(self.buf[..end].to_vec())
+                    .map_err(|_| S3Error::InvalidArgument)?;
+                self.buf.drain(..end + self.delimiter.len());
+
+                // a repeated field could be checked against the policy, then overridden
+                if self.fields.insert(name, value).is_some() {
+                    return Err(S3Error::InvalidArgument);
+                }
+
+                self.state = State::Delimited;
+                Ok(true)
+            }
+            State::File => match self.find(&self.delimiter) {
+                Some(end) => {
+                    out.extend(self.buf.drain(..end));
+                    self.buf.clear();
+                    Ok(true)
+                }
+                None => {
+                    // hold back anything which might be the start of the delimiter
+                    let available = self.buf.len().saturating_sub(self.delimiter.len());
+                    out.extend(self.buf.drain(..available));
+                    self.state = State::File;
+                    Ok(false)
+                }
+            },
+            State::Done => {
+                self.buf.clear();
+                Ok(false)
+            }
+        }
+    }
+
+    fn find(&self, needle: &[u8]) -> Option<usize> {
+        self.buf
+            .windows(needle.len())
+            .position(|window| needle == window)
+    }
+
+    /// Fail if `len` more bytes of fields would be too many.
+    fn check_size(&self, len: usize) -> Result<(), S3Error> {
+        if self.read + len > MAX_FIELDS {
+            return Err(S3Error::MaxPostPreDataLengthExceededError);
+        }
+        Ok(())
+    }
+}
+
+/// Send the rest of the file to the `sender`, starting with the data already decoded, `first`.
+///
+/// Fails if the file is longer than `limit`, or the form is broken; the file then ends early,
+/// so whatever is reading it must check with us before using it.
+pub async fn forward(
+    mut body: hyper::Body,
+    mut decoder: Decoder,
+    first: Vec<u8>,
+    mut sender: Sender,
+    limit: u64,
+) -> Result<(), Error> {
+    let mut length = 0;
+    let mut data = first;
+    loop {
+        length += u64::try_from(data.len())?;
+        if length > limit {
+            return Err(S3Error::EntityTooLarge.into());
+        }
+
+        if !data.is_empty() {
+            sender.send_data(data.into()).await?;
+        }
+
+        data = match body.data().await {
+            Some(data) => decoder.push(&data?)?,
+            None => break,
+        };
+    }
+
+    decoder.finish()?;
+    Ok(())
+}
+
+/// The (lower-case) `name` and `filename` from a part's `content-disposition` header.
+fn disposition(headers: &str) -> Option<(String, Option<String>)> {
+    let value = headers.split("\r\n").find_map(|line| {
+        let colon = line.find(':')?;
+        if line[..colon]
+            .trim()
+            .eq_ignore_ascii_case("content-disposition")
+        {
+            Some(&line[colon + 1..])
+        } else {
+            None
+        }
+    })?;
+
+    let mut params = value.split(';').map(|param| param.trim());
+    if !params.next()?.eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+
+    let mut name = None;
+    let mut filename = None;
+    for param in params {
+        let eq = match param.find('=') {
+            Some(eq) => eq,
+            None => continue,
+        };
+        let value = param[eq + 1..].trim().trim_matches('"').to_string();
+        match param[..eq].trim().to_ascii_lowercase().as_str() {
+            "name" => name = Some(value.to_ascii_lowercase()),
+            "filename" => filename = Some(value),
+            _ => (),
+        }
+    }
+
+    Some((name?, filename))
+}
+
+#[test]
+fn decoding() {
+    let body = b"ignored\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"Key\"\r\n\r\n\
+        uploads/${filename}\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"x-amz-meta-tag\"\r\n\r\n\
+        a\r\nb\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        hello\r\n--xy world\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"ignored\"\r\n\r\n\
+        value\r\n--xyz--\r\n";
+
+    // split awkwardly, across delimiters
+    for &size in &[1, 7, body.len()] {
+        let mut decoder = Decoder::new("multipart/form-data; boundary=xyz").expect("valid");
+        let mut file = Vec::new();
+        for piece in body.chunks(size) {
+            file.extend(decoder.push(piece).expect("valid form"));
+        }
+
+        let fields = decoder.fields().expect("file reached");
+        assert_eq!("uploads/${filename}", fields["key"]);
+        assert_eq!("a\r\nb", fields["x-amz-meta-tag"]);
+        assert!(!fields.contains_key("ignored"));
+        assert_eq!("a.txt", decoder.filename());
+        assert_eq!(&b"hello\r\n--xy world"[..], &file[..]);
+        decoder.finish().expect("complete");
+    }
+
+    let mut decoder = Decoder::new("multipart/form-data; boundary=xyz").expect("valid");
+    decoder.push(&body[..260]).expect("valid so far");
+    assert_eq!(Err(S3Error::IncompleteBody), decoder.finish());
+
+    let mut decoder = Decoder::new("multipart/form-data; boundary=\"xyz\"").expect("valid");
+    decoder.push(&body[..100]).expect("valid so far");
+    assert!(decoder.fields().is_none());
+    assert_eq!(Err(S3Error::InvalidArgument), decoder.finish());
+
+    assert!(Decoder::new("multipart/mixed; boundary=xyz").is_none());
+    assert!(Decoder::new("multipart/form-data").is_none());
+}
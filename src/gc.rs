@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use tokio::sync::Mutex;
+
+use super::bucket;
+use super::bucket::LifecyclePolicy;
+use super::bucket::VersioningPolicy;
+use super::dir;
+use super::lifecycle::Rule;
+use super::multipart;
+
+/// Apply every bucket's lifecycle policy and rules.
+///
+/// Returns how many keys were expired, and versions or uploads removed.
+pub async fn sweep(storage: &Path, meta_lock: &Mutex<()>) -> Result<usize, Error> {
+    let now = Utc::now();
+    let mut removed = 0;
+    for (name, config) in bucket::list(storage).await? {
+        let store = name.dir(storage);
+
+        for rule in config.rules().iter().filter(|rule| rule.enabled()) {
+            let applied = apply(&store, meta_lock, config.versioning(), rule, now).await?;
+            log::debug!("applied {} changes to {:?} for {:?}", applied, name, rule);
+            removed += applied;
+        }
+
+        if LifecyclePolicy::CollectOlder == config.lifecycle() {
+            let collected = dir::collect(&store, meta_lock, dir::FileMeta::superseded).await?;
+            log::debug!("collected {} versions from {:?}", collected, name);
+            removed += collected;
+        }
+    }
+    Ok(removed)
+}
+
+/// Expire objects, purge noncurrent versions, and abort uploads, as the rule asks.
+async fn apply(
+    store: &Path,
+    meta_lock: &Mutex<()>,
+    versioning: VersioningPolicy,
+    rule: &Rule,
+    now: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let mut changed = 0;
+
+    if let Some(cutoff) = rule.expiration_cutoff(now) {
+        for meta in dir::list(store).await? {
+            if !meta.key().starts_with(rule.prefix()) || meta.deleted()? {
+                continue;
+            }
+            if meta.latest_version()?.modified() >= cutoff {
+                continue;
+            }
+            // it may have been rewritten since we listed it
+            let expired = dir::delete_if(store, meta_lock, meta.key(), versioning, |live| {
+                live.modified() < cutoff
+            })
+            .await?;
+            if expired {
+                changed += 1;
+            }
+        }
+    }
+
+    if let Some(cutoff) = rule.noncurrent_cutoff(now) {
+        changed += dir::collect(store, meta_lock, |meta| {
+            Ok(if meta.key().starts_with(rule.prefix()) {
+                meta.noncurrent_before(cutoff)
+            } else {
+                Vec::new()
+            })
+        })
+        .await?;
+    }
+
+    if let Some(cutoff) = rule.abort_incomplete_cutoff(now) {
+        changed += multipart::abort_stale(store, rule.prefix(), cutoff).await?;
+    }
+
+    Ok(changed)
+}
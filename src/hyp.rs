@@ -11,6 +11,7 @@ pub fn method(method: &hyper::Method) -> Option<SimpleMethod> {
         hyper::Method::PUT => SimpleMethod::Put,
         hyper::Method::POST => SimpleMethod::Post,
         hyper::Method::DELETE => SimpleMethod::Delete,
+        hyper::Method::HEAD => SimpleMethod::Head,
         _ => return None,
     })
 }
@@ -19,10 +20,37 @@ pub fn path(req: &hyper::Request<Body>) -> &str {
     req.uri().path()
 }
 
+/// The `host` the request was sent to, including any port.
+pub fn host(req: &hyper::Request<Body>) -> Option<&str> {
+    match req.headers().get("host") {
+        Some(host) => host.to_str().ok(),
+        None => req.uri().host(),
+    }
+}
+
 pub fn query(req: &hyper::Request<Body>) -> &str {
     req.uri().query().unwrap_or("")
 }
 
+/// The decoded query parameters; valueless parameters (`?delete`) map to the empty string.
+pub fn query_map(req: &hyper::Request<Body>) -> Result<HashMap<String, String>, Error> {
+    let mut ret = HashMap::new();
+    for pair in query(req).split('&').filter(|pair| !pair.is_empty()) {
+        let (k, v) = match pair.find('=') {
+            Some(eq) => (&pair[..eq], &pair[eq + 1..]),
+            None => (pair, ""),
+        };
+        ret.insert(decode(k)?, decode(v)?);
+    }
+    Ok(ret)
+}
+
+pub fn decode(value: &str) -> Result<String, Error> {
+    Ok(percent_encoding::percent_decode_str(value)
+        .decode_utf8()?
+        .to_string())
+}
+
 pub fn headers(req: &hyper::Request<Body>) -> Result<HashMap<String, String>, Error> {
     let orig = req.headers();
     let mut ret = HashMap::with_capacity(orig.keys_len());
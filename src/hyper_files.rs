@@ -1,62 +1,267 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::pin::Pin;
 
 use failure::Error;
 use hyper::body::Buf;
+use hyper::body::Bytes;
 use hyper::body::HttpBody;
 use hyper::body::Sender;
 use md5::digest::FixedOutput;
 use md5::digest::Input;
 use tokio::io::AsyncReadExt as _;
+use tokio::io::AsyncSeek;
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt as _;
 use tokio::prelude::AsyncRead;
 use zstd::stream::raw::Operation;
 
+use super::checksum::Expected;
+use super::chunked;
 use super::dir::ContentInfo;
+use super::dir::FrameIndex;
+use super::error::S3Error;
+use super::range::Part;
+use super::sig::Payload;
 
+/// The uncompressed size of each independently decompressable frame.
+const FRAME_SIZE: u64 = 1024 * 1024;
+
+type Encoder = zstd::stream::Encoder<io::Cursor<Vec<u8>>>;
+
+/// Compress the body into `out`, decoding it, and failing if it doesn't match the `payload`,
+/// or the digests we `expected`.
 pub async fn stream_pack<W: Unpin + AsyncWrite>(
     mut body: hyper::Body,
     mut out: W,
+    payload: Payload,
+    expected: &Expected,
 ) -> Result<ContentInfo, Error> {
-    let mut enc = zstd::stream::Encoder::new(io::Cursor::new(Vec::with_capacity(8 * 1024)), 3)?;
-    enc.include_checksum(true)?;
+    let (sha256, decoded_length, mut decoder) = decoding(payload);
 
     let mut length = 0;
     let mut md5 = md5::Md5::default();
+    let mut sha = sha2::Sha256::default();
+    let mut checksums = expected.hasher();
+
+    let mut written = 0;
+    let mut offsets = Vec::new();
+    let mut enc: Option<(Encoder, u64)> = None;
 
     while let Some(data) = body.data().await {
         // typically 8 - 128kB chunks
-        let mut data = data?;
+        let data = data?;
+        let mut data = match &mut decoder {
+            Some(decoder) => Bytes::from(decoder.push(&data)?),
+            None => data,
+        };
         md5.input(&data);
+        sha.input(&data);
+        checksums.input(&data);
         length += u64::try_from(data.len())?;
 
         while !data.is_empty() {
-            let written = enc.write(&data)?;
-            data.advance(written);
-            let cursor = enc.get_mut();
+            if enc.is_none() {
+                offsets.push(written);
+                enc = Some((new_encoder()?, 0));
+            }
+
+            let (frame, in_frame) = enc.as_mut().expect("just populated");
+            let room = usize::try_from(FRAME_SIZE - *in_frame).unwrap_or(usize::MAX);
+            let consumed = frame.write(&data[..data.len().min(room)])?;
+            data.advance(consumed);
+            *in_frame += u64::try_from(consumed)?;
+
+            let cursor = frame.get_mut();
             let vec = cursor.get_mut();
 
             // frequently (for compressible data), the write has not caused any new frames
             if !vec.is_empty() {
                 out.write_all(vec).await?;
+                written += u64::try_from(vec.len())?;
                 vec.clear();
                 cursor.set_position(0);
             }
+
+            if FRAME_SIZE == *in_frame {
+                let (frame, _) = enc.take().expect("just used");
+                written += finish_frame(frame, &mut out).await?;
+            }
         }
     }
 
-    out.write_all(enc.finish()?.get_ref()).await?;
+    // an empty body is still stored as a (single, empty) frame
+    if offsets.is_empty() {
+        offsets.push(written);
+        enc = Some((new_encoder()?, 0));
+    }
+
+    if let Some((frame, _)) = enc {
+        finish_frame(frame, &mut out).await?;
+    }
+
+    let trailers = match decoder {
+        Some(decoder) => decoder.finish()?,
+        None => HashMap::new(),
+    };
+
+    if let Some(expected) = decoded_length {
+        if expected != length {
+            return Err(S3Error::IncompleteBody.into());
+        }
+    }
+
+    let sha = sha.fixed_result();
+    if let Some(expected) = sha256 {
+        if expected != sha.as_slice() {
+            return Err(S3Error::XAmzContentSHA256Mismatch.into());
+        }
+    }
 
     let md5_base64 = base64::encode(&md5.fixed_result());
+    let checksums = checksums.finish(&sha);
+    expected.check(&md5_base64, &checksums, &trailers)?;
+
+    Ok(ContentInfo {
+        length,
+        md5_base64,
+        frames: FrameIndex {
+            frame_size: FRAME_SIZE,
+            offsets,
+            starts: Vec::new(),
+        },
+        parts: None,
+        checksums,
+    })
+}
+
+/// Read a (small) body into memory, decoding it, and failing if it doesn't match the `payload`;
+/// or `None` if it's longer than `limit`.
+pub async fn payload_bytes(
+    mut body: hyper::Body,
+    payload: Payload,
+    limit: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    let (sha256, decoded_length, mut decoder) = decoding(payload);
+
+    let mut ret = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data?;
+        let data = match &mut decoder {
+            Some(decoder) => Bytes::from(decoder.push(&data)?),
+            None => data,
+        };
+        if ret.len() + data.len() > limit {
+            return Ok(None);
+        }
+        ret.extend_from_slice(&data);
+    }
+
+    // nothing asks for checksums of these bodies, so any trailers are only checked as signed
+    if let Some(decoder) = decoder {
+        decoder.finish()?;
+    }
+
+    if let Some(expected) = decoded_length {
+        if expected != u64::try_from(ret.len())? {
+            return Err(S3Error::IncompleteBody.into());
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        let mut sha = sha2::Sha256::default();
+        sha.input(&ret);
+        if expected != sha.fixed_result().as_slice() {
+            return Err(S3Error::XAmzContentSHA256Mismatch.into());
+        }
+    }
+
+    Ok(Some(ret))
+}
+
+/// The SHA-256 and decoded length the body must have, and how to decode it.
+fn decoding(payload: Payload) -> (Option<Vec<u8>>, Option<u64>, Option<chunked::Decoder>) {
+    match payload {
+        Payload::Unsigned => (None, None, None),
+        Payload::Sha256(hash) => (Some(hash), None, None),
+        Payload::Chunked {
+            signer,
+            trailer,
+            decoded_length,
+        } => (
+            None,
+            decoded_length,
+            Some(chunked::Decoder::new(signer, trailer)),
+        ),
+    }
+}
+
+fn new_encoder() -> Result<Encoder, Error> {
+    let mut enc = zstd::stream::Encoder::new(io::Cursor::new(Vec::with_capacity(8 * 1024)), 3)?;
+    enc.include_checksum(true)?;
+    Ok(enc)
+}
 
-    Ok(ContentInfo { length, md5_base64 })
+async fn finish_frame<W: Unpin + AsyncWrite>(enc: Encoder, out: &mut W) -> Result<u64, Error> {
+    let cursor = enc.finish()?;
+    out.write_all(cursor.get_ref()).await?;
+    Ok(u64::try_from(cursor.get_ref().len())?)
 }
 
 pub async fn stream_unpack<R: Unpin + AsyncRead>(
     mut from: R,
     mut sender: Sender,
+) -> Result<(), Error> {
+    unpack(&mut from, &mut sender, 0, u64::MAX).await
+}
+
+/// Send some ranges of a file written by `stream_pack`, each preceded by its preamble.
+pub async fn stream_unpack_ranges<R: Unpin + AsyncRead + AsyncSeek>(
+    mut from: R,
+    mut sender: Sender,
+    frames: FrameIndex,
+    parts: Vec<Part>,
+    trailer: Vec<u8>,
+) -> Result<(), Error> {
+    for part in parts {
+        if !part.preamble.is_empty() {
+            sender.send_data(part.preamble.into()).await?;
+        }
+        let (offset, skip) = frames.seek_for(part.start);
+        seek(&mut from, SeekFrom::Start(offset)).await?;
+        unpack(&mut from, &mut sender, skip, part.len).await?;
+    }
+
+    if !trailer.is_empty() {
+        sender.send_data(trailer.into()).await?;
+    }
+
+    Ok(())
+}
+
+/// `AsyncSeekExt::seek` returns `Pending` without arranging a wake-up once the seek has
+/// started, so drive the seek ourselves.
+async fn seek<S: Unpin + AsyncSeek>(from: &mut S, pos: SeekFrom) -> io::Result<u64> {
+    let mut pos = Some(pos);
+    futures::future::poll_fn(|cx| {
+        if let Some(start) = pos {
+            futures::ready!(Pin::new(&mut *from).start_seek(cx, start))?;
+            pos = None;
+        }
+        Pin::new(&mut *from).poll_complete(cx)
+    })
+    .await
+}
+
+/// Decompress from the current position, discarding `skip` bytes then sending up to `take`.
+async fn unpack<R: Unpin + AsyncRead>(
+    from: &mut R,
+    sender: &mut Sender,
+    mut skip: u64,
+    mut take: u64,
 ) -> Result<(), Error> {
     let mut dec = zstd::stream::raw::Decoder::new()?;
     let mut inp = Vec::with_capacity(16 * 1024);
@@ -73,13 +278,31 @@ pub async fn stream_unpack<R: Unpin + AsyncRead>(
             let mut buf = [0u8; 16 * 1024];
             let status = dec.run_on_buffers(&inp, &mut buf)?;
             inp.drain(..status.bytes_read);
-            if 0 == status.bytes_written {
+            if 0 == status.bytes_written && 0 == status.bytes_read {
                 break;
             }
 
-            sender
-                .send_data(buf[..status.bytes_written].to_vec().into())
-                .await?;
+            let mut output = &buf[..status.bytes_written];
+
+            let skipping = usize::try_from(skip)
+                .unwrap_or(usize::MAX)
+                .min(output.len());
+            output = &output[skipping..];
+            skip -= u64::try_from(skipping)?;
+
+            let taking = usize::try_from(take)
+                .unwrap_or(usize::MAX)
+                .min(output.len());
+            output = &output[..taking];
+            take -= u64::try_from(taking)?;
+
+            if !output.is_empty() {
+                sender.send_data(output.to_vec().into()).await?;
+            }
+
+            if 0 == take {
+                return Ok(());
+            }
         }
 
         if 0 == found {
@@ -94,3 +317,149 @@ pub async fn stream_unpack<R: Unpin + AsyncRead>(
         }
     }
 }
+
+#[tokio::test]
+async fn round_trip() {
+    // not very compressible, and spanning a few frames
+    let mut data = Vec::with_capacity(5 * 1024 * 1024 / 2);
+    let mut state = 7u32;
+    while data.len() < data.capacity() {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        data.push((state >> 16) as u8);
+    }
+
+    let mut packed = Vec::new();
+    let content = stream_pack(
+        hyper::Body::from(data.clone()),
+        &mut packed,
+        Payload::Unsigned,
+        &Expected::default(),
+    )
+    .await
+    .expect("packing to memory");
+    assert_eq!(data.len() as u64, content.length);
+    assert_eq!(3, content.frames.offsets.len());
+
+    let ranges = vec![
+        (0, 10),
+        (FRAME_SIZE - 5, FRAME_SIZE + 5),
+        (2_500_000, 2_600_000),
+    ];
+    for &(start, end) in &ranges {
+        let (sender, body) = hyper::Body::channel();
+        let sending = tokio::spawn(stream_unpack_ranges(
+            io::Cursor::new(packed.clone()),
+            sender,
+            content.frames.clone(),
+            vec![Part {
+                preamble: Vec::new(),
+                start,
+                len: end - start + 1,
+            }],
+            Vec::new(),
+        ));
+        let received = hyper::body::to_bytes(body).await.expect("receiving");
+        sending.await.expect("joining").expect("sending");
+        let end = (end as usize).min(data.len() - 1);
+        assert_eq!(&data[start as usize..=end], &received[..]);
+    }
+
+    let (sender, body) = hyper::Body::channel();
+    tokio::spawn(stream_unpack(io::Cursor::new(packed), sender));
+    let received = hyper::body::to_bytes(body).await.expect("receiving");
+    assert_eq!(data, received);
+}
+
+#[tokio::test]
+async fn declared_hash() {
+    let body = || hyper::Body::from(&b"hello"[..]);
+    let hello = hex::decode("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        .expect("static");
+
+    let expected = Expected::default();
+    stream_pack(body(), &mut Vec::new(), Payload::Sha256(hello), &expected)
+        .await
+        .expect("matching hash");
+
+    let e = stream_pack(
+        body(),
+        &mut Vec::new(),
+        Payload::Sha256(vec![0u8; 32]),
+        &expected,
+    )
+    .await
+    .expect_err("wrong hash");
+    assert_eq!(
+        Some(&S3Error::XAmzContentSHA256Mismatch),
+        e.downcast_ref::<S3Error>()
+    );
+}
+
+#[tokio::test]
+async fn chunked_body() {
+    let payload = Payload::Chunked {
+        signer: None,
+        trailer: true,
+        decoded_length: Some(5),
+    };
+    let body = hyper::Body::from(
+        &b"5\r\nhello\r\n0\r\n\
+        x-amz-checksum-sha256:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=\r\n\r\n"[..],
+    );
+
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert(
+        "x-amz-trailer",
+        hyper::header::HeaderValue::from_static("x-amz-checksum-sha256"),
+    );
+    let expected = Expected::from_headers(&headers).expect("valid");
+
+    let mut packed = Vec::new();
+    let content = stream_pack(body, &mut packed, payload, &expected)
+        .await
+        .expect("valid body");
+    assert_eq!(5, content.length);
+    assert!(!content.checksums.is_empty());
+
+    let (sender, body) = hyper::Body::channel();
+    tokio::spawn(stream_unpack(io::Cursor::new(packed), sender));
+    let received = hyper::body::to_bytes(body).await.expect("receiving");
+    assert_eq!(&b"hello"[..], &received[..]);
+}
+
+#[tokio::test]
+async fn buffered_payload() {
+    let hello = hex::decode("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        .expect("static");
+    let body = payload_bytes(hyper::Body::from("hello"), Payload::Sha256(hello), 5)
+        .await
+        .expect("matching hash");
+    assert_eq!(Some(b"hello".to_vec()), body);
+
+    let e = payload_bytes(
+        hyper::Body::from("hello"),
+        Payload::Sha256(vec![0u8; 32]),
+        5,
+    )
+    .await
+    .expect_err("wrong hash");
+    assert_eq!(
+        Some(&S3Error::XAmzContentSHA256Mismatch),
+        e.downcast_ref::<S3Error>()
+    );
+
+    let chunked = || Payload::Chunked {
+        signer: None,
+        trailer: false,
+        decoded_length: Some(5),
+    };
+    let body = || hyper::Body::from("5\r\nhello\r\n0\r\n\r\n");
+    let decoded = payload_bytes(body(), chunked(), 5)
+        .await
+        .expect("valid body");
+    assert_eq!(Some(b"hello".to_vec()), decoded);
+    let long = payload_bytes(body(), chunked(), 4)
+        .await
+        .expect("valid body");
+    assert_eq!(None, long);
+}
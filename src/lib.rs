@@ -1,8 +1,23 @@
-mod bucket;
+pub mod bucket;
+mod checksum;
+mod chunked;
+mod cond;
+mod delete;
 pub mod dir;
+pub mod error;
+mod form;
+pub mod gc;
 mod hyp;
 pub mod hyper_files;
+mod lifecycle;
+mod list;
+mod multipart;
+pub mod post;
+mod range;
 pub mod reqs;
+pub mod roles;
 mod sig;
 mod temp;
 pub mod users;
+mod versions;
+mod xml;
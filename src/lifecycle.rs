@@ -0,0 +1,266 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use serde::de::IgnoredAny;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use super::xml::Xml;
+
+/// S3 refuses configurations with more rules than this.
+const MAX_RULES: usize = 1000;
+
+/// A lifecycle rule, as stored in the bucket's config.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    id: String,
+    prefix: String,
+    enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiration_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    noncurrent_days: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    abort_incomplete_days: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Configuration {
+    #[serde(rename = "Rule", default)]
+    rules: Vec<RawRule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawRule {
+    #[serde(rename = "ID")]
+    id: Option<String>,
+    /// The deprecated way of specifying the prefix, outside of a `Filter`.
+    prefix: Option<String>,
+    filter: Option<Filter>,
+    status: String,
+    expiration: Option<Expiration>,
+    noncurrent_version_expiration: Option<NoncurrentVersionExpiration>,
+    abort_incomplete_multipart_upload: Option<AbortIncompleteMultipartUpload>,
+    transition: Option<IgnoredAny>,
+    noncurrent_version_transition: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Filter {
+    prefix: Option<String>,
+    /// Tag filters aren't supported; we mustn't expire more than was asked for.
+    tag: Option<IgnoredAny>,
+    and: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Expiration {
+    days: Option<u32>,
+    date: Option<IgnoredAny>,
+    expired_object_delete_marker: Option<IgnoredAny>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NoncurrentVersionExpiration {
+    noncurrent_days: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AbortIncompleteMultipartUpload {
+    days_after_initiation: u32,
+}
+
+impl RawRule {
+    fn validate(self) -> Option<Rule> {
+        let enabled = match self.status.as_str() {
+            "Enabled" => true,
+            "Disabled" => false,
+            _ => return None,
+        };
+
+        if self.transition.is_some() || self.noncurrent_version_transition.is_some() {
+            return None;
+        }
+
+        let prefix = match (self.prefix, self.filter) {
+            (Some(_), Some(_)) => return None,
+            (Some(prefix), None) => prefix,
+            (None, Some(filter)) => {
+                if filter.tag.is_some() || filter.and.is_some() {
+                    return None;
+                }
+                filter.prefix.unwrap_or_default()
+            }
+            (None, None) => String::new(),
+        };
+
+        let expiration_days = match self.expiration {
+            Some(expiration) => {
+                if expiration.date.is_some() || expiration.expired_object_delete_marker.is_some() {
+                    return None;
+                }
+                Some(expiration.days?)
+            }
+            None => None,
+        };
+
+        let rule = Rule {
+            id: self.id.unwrap_or_default(),
+            prefix,
+            enabled,
+            expiration_days,
+            noncurrent_days: self
+                .noncurrent_version_expiration
+                .map(|expiration| expiration.noncurrent_days),
+            abort_incomplete_days: self
+                .abort_incomplete_multipart_upload
+                .map(|abort| abort.days_after_initiation),
+        };
+
+        let days = [
+            rule.expiration_days,
+            rule.noncurrent_days,
+            rule.abort_incomplete_days,
+        ];
+
+        // every rule needs an action, and the actions need to be in the future
+        if days.iter().all(Option::is_none) || days.contains(&Some(0)) {
+            return None;
+        }
+
+        Some(rule)
+    }
+}
+
+/// Parse the body of a PutBucketLifecycleConfiguration request, if it's acceptable.
+pub fn from_xml(body: &[u8]) -> Option<Vec<Rule>> {
+    let config: Configuration = serde_xml_rs::from_reader(body).ok()?;
+    if config.rules.is_empty() || config.rules.len() > MAX_RULES {
+        return None;
+    }
+    config.rules.into_iter().map(RawRule::validate).collect()
+}
+
+/// Render a GetBucketLifecycleConfiguration response.
+pub fn configuration(rules: &[Rule]) -> String {
+    let mut xml = Xml::new("LifecycleConfiguration");
+    for rule in rules {
+        xml.open("Rule");
+        if !rule.id.is_empty() {
+            xml.text("ID", &rule.id);
+        }
+        xml.open("Filter");
+        xml.text("Prefix", &rule.prefix);
+        xml.close("Filter");
+        xml.text("Status", if rule.enabled { "Enabled" } else { "Disabled" });
+        if let Some(days) = rule.expiration_days {
+            xml.open("Expiration");
+            xml.text("Days", days);
+            xml.close("Expiration");
+        }
+        if let Some(days) = rule.noncurrent_days {
+            xml.open("NoncurrentVersionExpiration");
+            xml.text("NoncurrentDays", days);
+            xml.close("NoncurrentVersionExpiration");
+        }
+        if let Some(days) = rule.abort_incomplete_days {
+            xml.open("AbortIncompleteMultipartUpload");
+            xml.text("DaysAfterInitiation", days);
+            xml.close("AbortIncompleteMultipartUpload");
+        }
+        xml.close("Rule");
+    }
+    xml.finish()
+}
+
+impl Rule {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Live objects last modified before this should be deleted.
+    pub fn expiration_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.expiration_days.map(|days| cutoff(now, days))
+    }
+
+    /// Versions which were superseded before this should be purged.
+    pub fn noncurrent_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.noncurrent_days.map(|days| cutoff(now, days))
+    }
+
+    /// Multipart uploads started before this should be aborted.
+    pub fn abort_incomplete_cutoff(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.abort_incomplete_days.map(|days| cutoff(now, days))
+    }
+}
+
+fn cutoff(now: DateTime<Utc>, days: u32) -> DateTime<Utc> {
+    now - Duration::days(i64::from(days))
+}
+
+#[test]
+fn parse() {
+    let rules = from_xml(
+        br#"<LifecycleConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+        <Rule>
+            <ID>scratch</ID>
+            <Filter><Prefix>ci/</Prefix></Filter>
+            <Status>Enabled</Status>
+            <Expiration><Days>14</Days></Expiration>
+            <AbortIncompleteMultipartUpload>
+                <DaysAfterInitiation>2</DaysAfterInitiation>
+            </AbortIncompleteMultipartUpload>
+        </Rule>
+        <Rule>
+            <Prefix>old/</Prefix>
+            <Status>Disabled</Status>
+            <NoncurrentVersionExpiration><NoncurrentDays>30</NoncurrentDays></NoncurrentVersionExpiration>
+        </Rule>
+        </LifecycleConfiguration>"#,
+    )
+    .expect("valid");
+
+    assert_eq!(2, rules.len());
+    assert_eq!("scratch", rules[0].id);
+    assert_eq!("ci/", rules[0].prefix());
+    assert!(rules[0].enabled());
+    assert_eq!(Some(14), rules[0].expiration_days);
+    assert_eq!(Some(2), rules[0].abort_incomplete_days);
+    assert_eq!(None, rules[0].noncurrent_days);
+    assert_eq!("old/", rules[1].prefix());
+    assert!(!rules[1].enabled());
+    assert_eq!(Some(30), rules[1].noncurrent_days);
+
+    let now = Utc::now();
+    assert_eq!(
+        Some(now - Duration::days(14)),
+        rules[0].expiration_cutoff(now)
+    );
+    assert_eq!(None, rules[1].expiration_cutoff(now));
+
+    // no action
+    assert!(from_xml(
+        b"<LifecycleConfiguration><Rule><Status>Enabled</Status></Rule></LifecycleConfiguration>"
+    )
+    .is_none());
+
+    // tag filters would expire more than was asked for
+    assert!(from_xml(
+        b"<LifecycleConfiguration><Rule><Filter><Tag><Key>a</Key><Value>b</Value></Tag></Filter>\
+        <Status>Enabled</Status><Expiration><Days>1</Days></Expiration></Rule>\
+        </LifecycleConfiguration>"
+    )
+    .is_none());
+
+    assert!(from_xml(b"<LifecycleConfiguration></LifecycleConfiguration>").is_none());
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use percent_encoding::AsciiSet;
+use percent_encoding::NON_ALPHANUMERIC;
+
+use super::bucket;
+use super::dir;
+use super::xml::Xml;
+
+const MAX_KEYS: usize = 1000;
+
+const URL_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Object {
+    pub key: String,
+    pub modified: DateTime<Utc>,
+    pub size: u64,
+    pub etag: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    prefix: String,
+    delimiter: Option<String>,
+    max_keys: usize,
+    start_after: Option<String>,
+    continuation_token: Option<String>,
+    url_encode: bool,
+}
+
+/// Where the previous page stopped; everything up to and including this has been returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Marker {
+    Key(String),
+    CommonPrefix(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Page {
+    contents: Vec<Object>,
+    common_prefixes: Vec<String>,
+    next: Option<Marker>,
+}
+
+impl Query {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Query, &'static str> {
+        let max_keys = match params.get("max-keys") {
+            Some(max) => max.parse::<usize>().map_err(|_| "invalid max-keys")?,
+            None => MAX_KEYS,
+        };
+
+        let url_encode = match params.get("encoding-type").map(|s| s.as_str()) {
+            Some("url") => true,
+            Some(_) => return Err("invalid encoding-type"),
+            None => false,
+        };
+
+        let query = Query {
+            prefix: params.get("prefix").cloned().unwrap_or_default(),
+            delimiter: params.get("delimiter").filter(|d| !d.is_empty()).cloned(),
+            max_keys: max_keys.min(MAX_KEYS),
+            start_after: params.get("start-after").cloned(),
+            continuation_token: params.get("continuation-token").cloned(),
+            url_encode,
+        };
+
+        // validate the token now, rather than when we're half way through
+        query.resume()?;
+
+        Ok(query)
+    }
+
+    fn resume(&self) -> Result<Option<Marker>, &'static str> {
+        Ok(match &self.continuation_token {
+            Some(token) => Some(Marker::from_token(token).ok_or("invalid continuation-token")?),
+            None => None,
+        })
+    }
+
+    fn encode<'s>(&self, value: &'s str) -> std::borrow::Cow<'s, str> {
+        if self.url_encode {
+            percent_encoding::utf8_percent_encode(value, URL_ENCODE).into()
+        } else {
+            value.into()
+        }
+    }
+}
+
+impl Marker {
+    fn to_token(&self) -> String {
+        let raw = match self {
+            Marker::Key(key) => format!("k{}", key),
+            Marker::CommonPrefix(prefix) => format!("p{}", prefix),
+        };
+        base64::encode_config(&raw, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn from_token(token: &str) -> Option<Marker> {
+        let raw = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+        let raw = String::from_utf8(raw).ok()?;
+        match raw.chars().next()? {
+            'k' => Some(Marker::Key(raw[1..].to_string())),
+            'p' => Some(Marker::CommonPrefix(raw[1..].to_string())),
+            _ => None,
+        }
+    }
+
+    fn passed(&self, key: &str) -> bool {
+        match self {
+            Marker::Key(last) => key <= last.as_str(),
+            Marker::CommonPrefix(last) => key <= last.as_str() || key.starts_with(last.as_str()),
+        }
+    }
+}
+
+/// The live (not deleted) objects in a bucket's store, sorted by key.
+pub async fn objects(root: &Path) -> Result<Vec<Object>, Error> {
+    let mut ret = Vec::new();
+    for meta in dir::list(root).await? {
+        if meta.deleted()? {
+            continue;
+        }
+        let version = meta.latest_version()?;
+        ret.push(Object {
+            key: meta.key().to_string(),
+            modified: version.modified(),
+            size: version.content_length(),
+            etag: version.etag()?,
+        });
+    }
+    ret.sort_by(|left, right| left.key.cmp(&right.key));
+    Ok(ret)
+}
+
+fn page(objects: Vec<Object>, query: &Query) -> Result<Page, &'static str> {
+    let resume = query.resume()?;
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut last = None;
+    let mut truncated = false;
+
+    for object in objects {
+        if !object.key.starts_with(&query.prefix) {
+            continue;
+        }
+
+        if let Some(after) = &query.start_after {
+            if object.key <= *after {
+                continue;
+            }
+        }
+
+        if let Some(resume) = &resume {
+            if resume.passed(&object.key) {
+                continue;
+            }
+        }
+
+        let rolled_up = query.delimiter.as_ref().and_then(|delimiter| {
+            object.key[query.prefix.len()..]
+                .find(delimiter.as_str())
+                .map(|pos| object.key[..query.prefix.len() + pos + delimiter.len()].to_string())
+        });
+
+        // keys are sorted, so everything under a common prefix is adjacent
+        if let Some(common) = &rolled_up {
+            if common_prefixes.last() == Some(common) {
+                continue;
+            }
+        }
+
+        if contents.len() + common_prefixes.len() >= query.max_keys {
+            truncated = true;
+            break;
+        }
+
+        match rolled_up {
+            Some(common) => {
+                last = Some(Marker::CommonPrefix(common.clone()));
+                common_prefixes.push(common);
+            }
+            None => {
+                last = Some(Marker::Key(object.key.clone()));
+                contents.push(object);
+            }
+        }
+    }
+
+    Ok(Page {
+        contents,
+        common_prefixes,
+        next: if truncated { last } else { None },
+    })
+}
+
+/// Render a ListObjectsV2 response, or the reason the query was unacceptable.
+pub fn list_v2(bucket: &str, objects: Vec<Object>, query: &Query) -> Result<String, &'static str> {
+    let page = page(objects, query)?;
+
+    let mut xml = Xml::new("ListBucketResult");
+    xml.text("Name", bucket);
+    xml.text("Prefix", query.encode(&query.prefix));
+    if let Some(delimiter) = &query.delimiter {
+        xml.text("Delimiter", query.encode(delimiter));
+    }
+    xml.text("MaxKeys", query.max_keys);
+    xml.text("KeyCount", page.contents.len() + page.common_prefixes.len());
+    xml.text("IsTruncated", page.next.is_some());
+    if query.url_encode {
+        xml.text("EncodingType", "url");
+    }
+    if let Some(after) = &query.start_after {
+        xml.text("StartAfter", query.encode(after));
+    }
+    if let Some(token) = &query.continuation_token {
+        xml.text("ContinuationToken", token);
+    }
+    if let Some(next) = &page.next {
+        xml.text("NextContinuationToken", next.to_token());
+    }
+
+    for object in &page.contents {
+        xml.open("Contents");
+        xml.text("Key", query.encode(&object.key));
+        xml.text(
+            "LastModified",
+            object.modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        );
+        xml.text("ETag", &object.etag);
+        xml.text("Size", object.size);
+        xml.text("StorageClass", "STANDARD");
+        xml.close("Contents");
+    }
+
+    for prefix in &page.common_prefixes {
+        xml.open("CommonPrefixes");
+        xml.text("Prefix", query.encode(prefix));
+        xml.close("CommonPrefixes");
+    }
+
+    Ok(xml.finish())
+}
+
+/// Render a ListBuckets response.
+pub fn list_buckets(owner: &str, buckets: &[(bucket::Name, bucket::BucketConfig)]) -> String {
+    let mut xml = Xml::new("ListAllMyBucketsResult");
+    xml.open("Owner");
+    xml.text("ID", owner);
+    xml.text("DisplayName", owner);
+    xml.close("Owner");
+    xml.open("Buckets");
+    for (name, config) in buckets {
+        xml.open("Bucket");
+        xml.text("Name", name.as_str());
+        xml.text(
+            "CreationDate",
+            config.created().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        );
+        xml.close("Bucket");
+    }
+    xml.close("Buckets");
+    xml.finish()
+}
+
+#[test]
+fn paging() {
+    let objects: Vec<Object> = ["a", "b/1", "b/2", "b/3", "c", "d/1"]
+        .iter()
+        .map(|key| Object {
+            key: key.to_string(),
+            modified: Utc::now(),
+            size: 0,
+            etag: String::new(),
+        })
+        .collect();
+
+    let keys = |page: &Page| -> Vec<String> {
+        page.contents
+            .iter()
+            .map(|o| o.key.to_string())
+            .chain(page.common_prefixes.iter().cloned())
+            .collect()
+    };
+
+    let mut query = Query::from_params(&HashMap::new()).expect("static");
+    query.delimiter = Some("/".to_string());
+    query.max_keys = 2;
+
+    let first = page(objects.clone(), &query).expect("valid");
+    assert_eq!(vec!["a", "b/"], keys(&first));
+    assert_eq!(Some(Marker::CommonPrefix("b/".to_string())), first.next);
+
+    query.continuation_token = first.next.map(|next| next.to_token());
+    let second = page(objects.clone(), &query).expect("valid");
+    assert_eq!(vec!["c", "d/"], keys(&second));
+    assert_eq!(None, second.next);
+
+    let mut query = Query::from_params(&HashMap::new()).expect("static");
+    query.prefix = "b/".to_string();
+    query.start_after = Some("b/1".to_string());
+    let page = page(objects, &query).expect("valid");
+    assert_eq!(vec!["b/2", "b/3"], keys(&page));
+    assert_eq!(None, page.next);
+}
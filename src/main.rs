@@ -3,9 +3,14 @@ use std::collections::HashMap;
 use std::convert::Infallible;
 use std::env;
 use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
+use failure::err_msg;
 use failure::Error;
+use futures::FutureExt as _;
 use hyper::service::make_service_fn;
 use hyper::service::service_fn;
 use hyper::Body;
@@ -14,10 +19,14 @@ use hyper::Response;
 use hyper::Server;
 use log::debug;
 use log::info;
+use swisher::bucket;
+use swisher::dir;
 use swisher::reqs::CopyState;
 use swisher::reqs::SimpleMethod;
+use swisher::roles;
 use swisher::users;
 use tokio::sync::mpsc;
+use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -29,22 +38,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let args = clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
-        .arg(clap::Arg::with_name("issue").long("issue"))
+        .arg(
+            clap::Arg::with_name("issue")
+                .long("issue")
+                .requires("policy"),
+        )
+        .arg(
+            clap::Arg::with_name("policy")
+                .long("policy")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("issue"),
+        )
+        .arg(
+            clap::Arg::with_name("lifecycle")
+                .long("lifecycle")
+                .takes_value(true)
+                .value_names(&["BUCKET", "POLICY"]),
+        )
+        .arg(
+            clap::Arg::with_name("migrate-into")
+                .long("migrate-into")
+                .takes_value(true)
+                .value_name("BUCKET"),
+        )
         .get_matches();
 
     let state = CopyState {
         master: users::MasterKey::new(&env::var("SWISHER_MASTER_KEY")?),
+        meta_lock: Box::leak(Box::new(Mutex::new(()))),
+        regions: Box::leak(regions().into_boxed_slice()),
+        base_domain: base_domain().map(|domain| &*Box::leak(domain.into_boxed_str())),
     };
 
-
     if args.is_present("issue") {
-        let access = state.master.access_key_for(users::RoleId::random());
+        // every key issued since roles has its policy stored; only older keys have none
+        let path = args.value_of("policy").expect("required by issue");
+        let policy: roles::Policy = serde_json::from_slice(&std::fs::read(path)?)?;
+        let role = users::RoleId::random();
+        roles::put(Path::new("."), role, &policy)
+            .await
+            .map_err(Error::compat)?;
+
+        let access = state.master.access_key_for(role);
         let secret = state.master.secret_key_for(&access);
 
         println!("{}\t{}", access, secret);
         return Ok(());
     }
 
+    if let Some(mut values) = args.values_of("lifecycle") {
+        let bucket = values.next().expect("two values");
+        let policy = values.next().expect("two values");
+        set_lifecycle(state.meta_lock, bucket, policy)
+            .await
+            .map_err(Error::compat)?;
+        println!("{} now has lifecycle policy {}", bucket, policy);
+        return Ok(());
+    }
+
+    if let Some(bucket) = args.value_of("migrate-into") {
+        let moved = migrate(bucket).await.map_err(Error::compat)?;
+        println!("migrated {} keys into {}", moved, bucket);
+        return Ok(());
+    }
+
+    tokio::spawn(collect_garbage(state.meta_lock));
+
     let addr = SocketAddr::from(([0, 0, 0, 0], 8202));
 
     let (shutdown, mut is_shutdown) = mpsc::channel::<()>(1);
@@ -85,31 +145,115 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 async fn catch_handler(
     req: Request<Body>,
     state: CopyState,
-    mut shutdown: mpsc::Sender<()>,
+    shutdown: mpsc::Sender<()>,
 ) -> Result<Response<Body>, Infallible> {
-    // TODO: was expecting to catch_panic here but hyper doesn't want to play
-    Ok(match handler(req, state).await {
-        Ok(response) => response,
-        Err(e) => {
+    // the handler's state is all on disk, so there's nothing left broken by a panic
+    let result = AssertUnwindSafe(handler(req, state, shutdown))
+        .catch_unwind()
+        .await;
+    Ok(match result {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
             log::error!("internal error: {:?}", e);
-            let success = attempt_shutdown(shutdown);
-            log::warn!("error, attempting shutdown, status: {:?}", success);
-            Response::builder()
-                .status(500)
-                .body(Body::empty())
-                .expect("static builder")
+            internal_error()
+        }
+        Err(_) => {
+            log::error!("handler panicked");
+            internal_error()
         }
     })
 }
 
+fn internal_error() -> Response<Body> {
+    Response::builder()
+        .status(500)
+        .body(Body::empty())
+        .expect("static builder")
+}
+
+/// The regions in `SWISHER_REGIONS`, like `eu-west-2,us-east-1`; the first is the default.
+fn regions() -> Vec<String> {
+    let configured = env::var("SWISHER_REGIONS").unwrap_or_default();
+    let regions: Vec<String> = configured
+        .split(',')
+        .map(|region| region.trim())
+        .filter(|region| !region.is_empty())
+        .map(|region| region.to_string())
+        .collect();
+
+    if regions.is_empty() {
+        return vec!["us-east-1".to_string()];
+    }
+    regions
+}
+
+/// The domain in `SWISHER_BASE_DOMAIN`, like `s3.example.com`, under which buckets are hosts.
+fn base_domain() -> Option<String> {
+    let configured = env::var("SWISHER_BASE_DOMAIN").ok()?;
+    let domain = configured.trim().trim_matches('.').to_ascii_lowercase();
+    if domain.is_empty() {
+        return None;
+    }
+    Some(domain)
+}
+
 fn attempt_shutdown(mut shutdown: mpsc::Sender<()>) -> bool {
     shutdown.try_send(()).is_ok()
 }
 
-async fn handler(req: Request<Body>, state: CopyState) -> Result<Response<Body>, Error> {
-    let response = swisher::reqs::handle(req, state).await?;
-    Ok(Response::builder()
-        .status(response.status)
-        .body(response.body)
-        .expect("static builder"))
+/// Periodically apply the buckets' lifecycle policies and rules.
+async fn collect_garbage(meta_lock: &'static Mutex<()>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match swisher::gc::sweep(Path::new("."), meta_lock).await {
+            Ok(removed) => info!("lifecycle sweep made {} changes", removed),
+            Err(e) => log::error!("lifecycle sweep failed: {:?}", e),
+        }
+    }
+}
+
+/// Move objects from before buckets had their own namespace into the named bucket.
+/// Choose whether the garbage collector removes `bucket`'s old versions and deleted keys.
+async fn set_lifecycle(meta_lock: &Mutex<()>, bucket: &str, policy: &str) -> Result<(), Error> {
+    let bucket = bucket::Name::from(bucket).ok_or_else(|| err_msg("invalid bucket name"))?;
+    let policy = match policy {
+        "Keep" => bucket::LifecyclePolicy::Keep,
+        "CollectOlder" => bucket::LifecyclePolicy::CollectOlder,
+        _ => return Err(err_msg("lifecycle policy must be Keep or CollectOlder")),
+    };
+    bucket::update_config(Path::new("."), meta_lock, &bucket, |config| {
+        config.set_lifecycle(policy)
+    })
+    .await
+}
+
+async fn migrate(bucket: &str) -> Result<usize, Error> {
+    let storage = Path::new(".");
+    let bucket = bucket::Name::from(bucket).ok_or_else(|| err_msg("invalid bucket name"))?;
+    if bucket::get_config(storage, &bucket).await?.is_none() {
+        bucket::put_config(storage, &bucket, &bucket::BucketConfig::default()).await?;
+    }
+    dir::migrate(storage, &bucket.dir(storage)).await
+}
+
+async fn handler(
+    req: Request<Body>,
+    state: CopyState,
+    shutdown: mpsc::Sender<()>,
+) -> Result<Response<Body>, Error> {
+    let response = match swisher::reqs::handle(req, state).await {
+        Ok(response) => response,
+        // the only errors it returns are `error::fatal`
+        Err(e) => {
+            let success = attempt_shutdown(shutdown);
+            log::warn!("fatal error, attempting shutdown, status: {:?}", success);
+            return Err(e);
+        }
+    };
+    let mut builder = Response::builder().status(response.status);
+    for (k, v) in response.headers {
+        builder = builder.header(k.as_str(), v.as_str());
+    }
+    Ok(builder.body(response.body)?)
 }
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use md5::digest::FixedOutput;
+use md5::digest::Input;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt as _;
+
+use super::checksum::Checksums;
+use super::checksum::Expected;
+use super::dir::ContentInfo;
+use super::dir::FrameIndex;
+use super::dir::Intermediate;
+use super::error::S3Error;
+use super::hyper_files;
+use super::sig::Payload;
+use super::temp::NamedTempFile;
+use super::xml::Xml;
+
+/// Parts are numbered from one to this.
+const MAX_PART_NUMBER: u64 = 10_000;
+
+/// Every part except the last must be at least this large.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The most parts listed in one response.
+const MAX_LIST_PARTS: usize = 1000;
+
+/// Why an upload operation was refused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    NoSuchUpload,
+    InvalidArgument,
+    InvalidPart,
+    InvalidPartOrder,
+    EntityTooSmall,
+}
+
+impl From<Refusal> for S3Error {
+    fn from(refusal: Refusal) -> S3Error {
+        match refusal {
+            Refusal::NoSuchUpload => S3Error::NoSuchUpload,
+            Refusal::InvalidArgument => S3Error::InvalidArgument,
+            Refusal::InvalidPart => S3Error::InvalidPart,
+            Refusal::InvalidPartOrder => S3Error::InvalidPartOrder,
+            Refusal::EntityTooSmall => S3Error::EntityTooSmall,
+        }
+    }
+}
+
+/// An in-progress upload, stored in `.uploads/<id>/upload.json` in the bucket.
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    key: String,
+    /// The (signed) headers the upload was created with, which the object will get.
+    meta: HashMap<String, String>,
+    initiated: DateTime<Utc>,
+}
+
+/// An uploaded part, stored as `<number>.json`, next to its packed data.
+#[derive(Serialize, Deserialize)]
+struct Part {
+    modified: DateTime<Utc>,
+    length: u64,
+    md5_base64: String,
+    frames: FrameIndex,
+    #[serde(default, skip_serializing_if = "Checksums::is_empty")]
+    checksums: Checksums,
+    /// The data's file name, unique to each upload of the part; older parts used `<number>.part`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+impl Part {
+    fn data_path(&self, dir: &Path, number: u64) -> PathBuf {
+        match &self.data {
+            Some(name) => dir.join(name),
+            None => part_path(dir, number, "part"),
+        }
+    }
+
+    fn etag(&self) -> Result<String, Error> {
+        Ok(format!(
+            "\"{}\"",
+            hex::encode(base64::decode(&self.md5_base64)?)
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Completion {
+    #[serde(rename = "Part", default)]
+    parts: Vec<CompletedPart>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CompletedPart {
+    part_number: u64,
+    e_tag: String,
+}
+
+impl Completion {
+    /// Parse the body of a CompleteMultipartUpload request, if it's acceptable.
+    pub fn from_xml(body: &[u8]) -> Option<Completion> {
+        let completion: Completion = serde_xml_rs::from_reader(body).ok()?;
+        if completion.parts.is_empty() {
+            return None;
+        }
+        Some(completion)
+    }
+}
+
+/// Upload ids are only ever generated by us, so anything else (like `..`) is rejected.
+fn upload_dir(store: &Path, upload_id: &str) -> Option<PathBuf> {
+    if 32 != upload_id.len() || !upload_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut dir = store.join(".uploads");
+    dir.push(upload_id);
+    Some(dir)
+}
+
+fn part_path(dir: &Path, number: u64, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", number, extension))
+}
+
+async fn load_upload(
+    store: &Path,
+    key: &str,
+    upload_id: &str,
+) -> Result<Option<(PathBuf, Upload)>, Error> {
+    let dir = match upload_dir(store, upload_id) {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let upload: Upload = match read_json(&dir.join("upload.json")).await? {
+        Some(upload) => upload,
+        None => return Ok(None),
+    };
+    if upload.key != key {
+        return Ok(None);
+    }
+    Ok(Some((dir, upload)))
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    match fs::read(path).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
+        Err(e) => Err(e)?,
+    }
+}
+
+async fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let mut temp = NamedTempFile::new_in(path.parent().expect("structured dir")).await?;
+    temp.write_all(&serde_json::to_vec(value)?).await?;
+    temp.into_temp_path()
+        .persist(path)
+        .await
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Start an upload, and render the InitiateMultipartUploadResult.
+pub async fn create(
+    store: &Path,
+    bucket: &str,
+    key: &str,
+    meta: HashMap<String, String>,
+) -> Result<String, Error> {
+    let upload_id = format!(
+        "{:016x}{:016x}",
+        rand::random::<u64>(),
+        rand::random::<u64>()
+    );
+    let dir = upload_dir(store, &upload_id).expect("generated ids are valid");
+    fs::create_dir_all(&dir).await?;
+
+    let upload = Upload {
+        key: key.to_string(),
+        meta,
+        initiated: Utc::now(),
+    };
+    write_json(&dir.join("upload.json"), &upload).await?;
+
+    let mut xml = Xml::new("InitiateMultipartUploadResult");
+    xml.text("Bucket", bucket);
+    xml.text("Key", key);
+    xml.text("UploadId", upload_id);
+    Ok(xml.finish())
+}
+
+/// Store a part, replacing any previous upload of the same part number, returning its etag.
+pub async fn upload_part(
+    store: &Path,
+    key: &str,
+    upload_id: &str,
+    part_number: &str,
+    body: hyper::Body,
+    payload: Payload,
+    expected: &Expected,
+) -> Result<Result<String, Refusal>, Error> {
+    let number = match part_number.parse::<u64>() {
+        Ok(number) if (1..=MAX_PART_NUMBER).contains(&number) => number,
+        _ => return Ok(Err(Refusal::InvalidArgument)),
+    };
+
+    let (dir, _) = match load_upload(store, key, upload_id).await? {
+        Some(upload) => upload,
+        None => return Ok(Err(Refusal::NoSuchUpload)),
+    };
+
+    let mut temp = NamedTempFile::new_in(&dir).await?;
+    let content = hyper_files::stream_pack(body, &mut temp, payload, expected).await?;
+    temp.flush().await?;
+
+    let part = Part {
+        modified: Utc::now(),
+        length: content.length,
+        md5_base64: content.md5_base64,
+        frames: content.frames,
+        checksums: content.checksums,
+        data: Some(format!("{}.{:016x}.part", number, rand::random::<u64>())),
+    };
+
+    // the data must be in place before the part is visible; any earlier upload of the part
+    // keeps its data, which a concurrent completion may be reading, until the upload is gone
+    temp.into_temp_path()
+        .persist(&part.data_path(&dir, number))
+        .await
+        .map_err(|e| e.error)?;
+    write_json(&part_path(&dir, number, "json"), &part).await?;
+
+    Ok(Ok(part.etag()?))
+}
+
+/// Join the requested parts into one packed file, ready to be stored with the upload's headers.
+///
+/// The upload is left in place; it should be `abort`ed once the object is stored.
+pub async fn complete(
+    store: &Path,
+    key: &str,
+    upload_id: &str,
+    completion: Completion,
+) -> Result<Result<(HashMap<String, String>, Intermediate), Refusal>, Error> {
+    let (dir, upload) = match load_upload(store, key, upload_id).await? {
+        Some(upload) => upload,
+        None => return Ok(Err(Refusal::NoSuchUpload)),
+    };
+
+    let mut parts = Vec::with_capacity(completion.parts.len());
+    let mut previous = 0;
+    for requested in &completion.parts {
+        if requested.part_number <= previous {
+            return Ok(Err(Refusal::InvalidPartOrder));
+        }
+        previous = requested.part_number;
+
+        let part: Part = match read_json(&part_path(&dir, requested.part_number, "json")).await? {
+            Some(part) => part,
+            None => return Ok(Err(Refusal::InvalidPart)),
+        };
+
+        if requested.e_tag.trim().trim_matches('"') != part.etag()?.trim_matches('"') {
+            return Ok(Err(Refusal::InvalidPart));
+        }
+
+        parts.push((requested.part_number, part));
+    }
+
+    let too_small = parts
+        .iter()
+        .rev()
+        .skip(1)
+        .any(|(_, part)| part.length < MIN_PART_SIZE);
+    if too_small {
+        return Ok(Err(Refusal::EntityTooSmall));
+    }
+
+    let mut temp = NamedTempFile::new_in(store).await?;
+    let mut md5s = md5::Md5::default();
+    let mut frames = FrameIndex::default();
+    let mut length = 0;
+    let mut written = 0;
+
+    for (number, part) in &parts {
+        let mut file = fs::File::open(part.data_path(&dir, *number)).await?;
+        frames.append(&part.frames, length, written);
+        written += tokio::io::copy(&mut file, &mut temp).await?;
+        length += part.length;
+        md5s.input(base64::decode(&part.md5_base64)?);
+    }
+    temp.flush().await?;
+
+    let checksums: Vec<&Checksums> = parts.iter().map(|(_, part)| &part.checksums).collect();
+
+    let content = ContentInfo {
+        length,
+        md5_base64: base64::encode(&md5s.fixed_result()),
+        frames,
+        parts: Some(parts.len() as u64),
+        checksums: Checksums::composite(&checksums),
+    };
+
+    Ok(Ok((
+        upload.meta,
+        Intermediate {
+            temp: temp.into_temp_path(),
+            content,
+        },
+    )))
+}
+
+pub fn complete_result(bucket: &str, key: &str, etag: &str) -> String {
+    let mut xml = Xml::new("CompleteMultipartUploadResult");
+    xml.text("Location", format!("/{}/{}", bucket, key));
+    xml.text("Bucket", bucket);
+    xml.text("Key", key);
+    xml.text("ETag", etag);
+    xml.finish()
+}
+
+/// Discard an upload, and all of its parts.
+pub async fn abort(store: &Path, key: &str, upload_id: &str) -> Result<Result<(), Refusal>, Error> {
+    let (dir, _) = match load_upload(store, key, upload_id).await? {
+        Some(upload) => upload,
+        None => return Ok(Err(Refusal::NoSuchUpload)),
+    };
+    fs::remove_dir_all(&dir).await?;
+    Ok(Ok(()))
+}
+
+/// Whether the bucket has any uploads which haven't been completed or aborted.
+pub async fn in_progress(store: &Path) -> Result<bool, Error> {
+    let mut entries = match fs::read_dir(store.join(".uploads")).await {
+        Ok(entries) => entries,
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => return Ok(false),
+        Err(e) => Err(e)?,
+    };
+    Ok(entries.next_entry().await?.is_some())
+}
+
+/// Discard the uploads of keys starting with `prefix` which were started before `cutoff`.
+///
+/// Returns how many were discarded.
+pub async fn abort_stale(
+    store: &Path,
+    prefix: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<usize, Error> {
+    let mut entries = match fs::read_dir(store.join(".uploads")).await {
+        Ok(entries) => entries,
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => return Ok(0),
+        Err(e) => Err(e)?,
+    };
+
+    let mut aborted = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let upload_id = match entry.file_name().to_str() {
+            Some(upload_id) => upload_id.to_string(),
+            None => continue,
+        };
+        let dir = match upload_dir(store, &upload_id) {
+            Some(dir) => dir,
+            None => continue,
+        };
+        let upload: Upload = match read_json(&dir.join("upload.json")).await? {
+            Some(upload) => upload,
+            None => continue,
+        };
+        if !upload.key.starts_with(prefix) || upload.initiated >= cutoff {
+            continue;
+        }
+        // it may have been completed or aborted since we read it; that's fine
+        if abort(store, &upload.key, &upload_id).await?.is_ok() {
+            aborted += 1;
+        }
+    }
+    Ok(aborted)
+}
+
+/// Render the ListPartsResult for the `part-number-marker` and `max-parts` in `params`.
+pub async fn list_parts(
+    store: &Path,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    params: &HashMap<String, String>,
+) -> Result<Result<String, Refusal>, Error> {
+    let marker = match params.get("part-number-marker").map(|v| v.parse::<u64>()) {
+        None => 0,
+        Some(Ok(marker)) => marker,
+        Some(Err(_)) => return Ok(Err(Refusal::InvalidArgument)),
+    };
+    let max_parts = match params.get("max-parts").map(|v| v.parse::<usize>()) {
+        None => MAX_LIST_PARTS,
+        Some(Ok(max)) => max.min(MAX_LIST_PARTS),
+        Some(Err(_)) => return Ok(Err(Refusal::InvalidArgument)),
+    };
+
+    let (dir, _) = match load_upload(store, key, upload_id).await? {
+        Some(upload) => upload,
+        None => return Ok(Err(Refusal::NoSuchUpload)),
+    };
+
+    let mut numbers = Vec::new();
+    let mut entries = fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if Some("json") != path.extension().and_then(|ext| ext.to_str()) {
+            continue;
+        }
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        match number {
+            Some(number) if number > marker => numbers.push(number),
+            _ => (),
+        }
+    }
+    numbers.sort();
+
+    let truncated = numbers.len() > max_parts;
+    numbers.truncate(max_parts);
+
+    let mut xml = Xml::new("ListPartsResult");
+    xml.text("Bucket", bucket);
+    xml.text("Key", key);
+    xml.text("UploadId", upload_id);
+    xml.text("PartNumberMarker", marker);
+    if let Some(last) = numbers.last() {
+        xml.text("NextPartNumberMarker", last);
+    }
+    xml.text("MaxParts", max_parts);
+    xml.text("IsTruncated", truncated);
+
+    for number in numbers {
+        // the part may have been replaced or aborted since we listed the directory
+        let part: Part = match read_json(&part_path(&dir, number, "json")).await? {
+            Some(part) => part,
+            None => continue,
+        };
+        xml.open("Part");
+        xml.text("PartNumber", number);
+        xml.text(
+            "LastModified",
+            part.modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        );
+        xml.text("ETag", part.etag()?);
+        xml.text("Size", part.length);
+        xml.close("Part");
+    }
+
+    Ok(Ok(xml.finish()))
+}
+
+#[test]
+fn parse() {
+    let completion = Completion::from_xml(
+        br#"<CompleteMultipartUpload xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+        <Part><ETag>"abc"</ETag><PartNumber>1</PartNumber></Part>
+        <Part><ETag>"def"</ETag><PartNumber>3</PartNumber></Part>
+        </CompleteMultipartUpload>"#,
+    )
+    .expect("valid");
+    assert_eq!(2, completion.parts.len());
+    assert_eq!(3, completion.parts[1].part_number);
+    assert_eq!("\"def\"", completion.parts[1].e_tag);
+
+    assert!(Completion::from_xml(b"<CompleteMultipartUpload/>").is_none());
+
+    let store = Path::new("store");
+    assert!(upload_dir(store, "../../../../../../../../../../etc").is_none());
+    assert!(upload_dir(store, &"0".repeat(32)).is_some());
+}
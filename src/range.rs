@@ -0,0 +1,144 @@
+/// More ranges than this and we just send the whole object.
+const MAX_RANGES: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// No (usable) range was requested, send the whole object.
+    Whole,
+    /// None of the requested ranges overlap the object.
+    Unsatisfiable,
+    /// The inclusive start and end of each requested range.
+    Parts(Vec<(u64, u64)>),
+}
+
+/// Interpret a `Range` header against an object of `length` bytes.
+pub fn parse(header: &str, length: u64) -> Ranges {
+    let header = header.trim();
+    let prefix = "bytes=";
+    if !header.starts_with(prefix) {
+        return Ranges::Whole;
+    }
+
+    let mut parts = Vec::new();
+    for spec in header[prefix.len()..].split(',') {
+        let spec = spec.trim();
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return Ranges::Whole,
+        };
+        let (start, end) = (&spec[..dash], &spec[dash + 1..]);
+
+        // a "-500" range is the last 500 bytes
+        if start.is_empty() {
+            let suffix = match end.parse::<u64>() {
+                Ok(suffix) => suffix,
+                Err(_) => return Ranges::Whole,
+            };
+            if 0 != suffix && 0 != length {
+                parts.push((length.saturating_sub(suffix), length - 1));
+            }
+            continue;
+        }
+
+        let start = match start.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return Ranges::Whole,
+        };
+
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return Ranges::Whole,
+            }
+        };
+
+        if start < length {
+            parts.push((start, end.min(length - 1)));
+        }
+    }
+
+    if parts.len() > MAX_RANGES {
+        return Ranges::Whole;
+    }
+
+    if parts.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+
+    Ranges::Parts(parts)
+}
+
+pub fn content_range(start: u64, end: u64, length: u64) -> String {
+    format!("bytes {}-{}/{}", start, end, length)
+}
+
+/// A range to send, preceded by some framing.
+pub struct Part {
+    pub preamble: Vec<u8>,
+    pub start: u64,
+    pub len: u64,
+}
+
+/// The layout of a `multipart/byteranges` response.
+pub struct Multipart {
+    pub boundary: String,
+    pub parts: Vec<Part>,
+    pub trailer: Vec<u8>,
+}
+
+impl Multipart {
+    pub fn new(ranges: &[(u64, u64)], content_type: &str, length: u64) -> Multipart {
+        let boundary = format!("{:016x}", rand::random::<u64>());
+        let parts = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, end))| Part {
+                preamble: format!(
+                    "{}--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+                    if 0 == i { "" } else { "\r\n" },
+                    boundary,
+                    content_type,
+                    content_range(start, end, length)
+                )
+                .into_bytes(),
+                start,
+                len: end - start + 1,
+            })
+            .collect();
+        let trailer = format!("\r\n--{}--\r\n", boundary).into_bytes();
+        Multipart {
+            boundary,
+            parts,
+            trailer,
+        }
+    }
+
+    pub fn content_length(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|part| part.preamble.len() as u64 + part.len)
+            .sum();
+        parts + self.trailer.len() as u64
+    }
+}
+
+#[test]
+fn parsing() {
+    use Ranges::*;
+    assert_eq!(Parts(vec![(0, 9)]), parse("bytes=0-9", 100));
+    assert_eq!(Parts(vec![(90, 99)]), parse("bytes=90-", 100));
+    assert_eq!(Parts(vec![(95, 99)]), parse("bytes=-5", 100));
+    assert_eq!(Parts(vec![(0, 99)]), parse("bytes=-500", 100));
+    assert_eq!(Parts(vec![(0, 99)]), parse("bytes=0-500", 100));
+    assert_eq!(Parts(vec![(0, 0), (5, 6)]), parse("bytes=0-0, 5-6", 100));
+    assert_eq!(Parts(vec![(5, 6)]), parse("bytes=5-6,200-300", 100));
+    assert_eq!(Unsatisfiable, parse("bytes=100-", 100));
+    assert_eq!(Unsatisfiable, parse("bytes=0-", 0));
+    assert_eq!(Unsatisfiable, parse("bytes=-0", 100));
+    assert_eq!(Whole, parse("bytes=9-0", 100));
+    assert_eq!(Whole, parse("bytes=a-b", 100));
+    assert_eq!(Whole, parse("lines=1-2", 100));
+}
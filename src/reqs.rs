@@ -1,136 +1,1326 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
 use std::path::Path;
 
 use chrono::Utc;
-use failure::bail;
 use failure::Error;
+use hyper::body::HttpBody as _;
 use hyper::Body;
 use hyper::Request;
-use warheadhateus::HttpRequestMethod;
+use maplit::hashmap;
+use tokio::sync::Mutex;
 
 use super::bucket;
+use super::bucket::BucketConfig;
+use super::bucket::VersioningPolicy;
+use super::checksum;
+use super::chunked;
+use super::cond;
+use super::cond::Conditions;
+use super::delete;
 use super::dir;
+use super::dir::FileVersion;
 use super::dir::Intermediate;
+use super::error;
+use super::error::S3Error;
+use super::form;
 use super::hyp;
+use super::hyper_files;
+use super::lifecycle;
+use super::list;
+use super::multipart;
+use super::post;
+use super::range;
+use super::range::Ranges;
+use super::roles;
+use super::roles::Action;
 use super::sig;
+use super::versions;
+use super::xml::Xml;
 use crate::sig::Validation;
 use crate::users::MasterKey;
 
 #[derive(Copy, Clone)]
 pub struct CopyState {
     pub master: MasterKey,
+    /// Held while reading and rewriting the metadata of any key.
+    pub meta_lock: &'static Mutex<()>,
+    /// The regions clients may sign requests for; the first is where new buckets are.
+    pub regions: &'static [String],
+    /// Requests to `<bucket>.<base_domain>` are for that bucket; others are path-style.
+    pub base_domain: Option<&'static str>,
 }
 
 pub struct SimpleResponse {
     pub status: u16,
+    pub headers: HashMap<String, String>,
     pub body: Body,
 }
 
+impl SimpleResponse {
+    fn empty(status: u16) -> SimpleResponse {
+        SimpleResponse {
+            status,
+            headers: HashMap::new(),
+            body: Body::empty(),
+        }
+    }
+
+    fn xml(xml: String) -> SimpleResponse {
+        SimpleResponse {
+            status: 200,
+            headers: hashmap! { "content-type".to_string() => "application/xml".to_string() },
+            body: Body::from(xml),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum SimpleMethod {
     Get,
     Put,
     Post,
     Delete,
+    Head,
 }
 
-fn bucket_name(path: &str) -> (&str, &str) {
+/// The bucket and (encoded) key, from the `host` if it's a subdomain of the `base_domain`,
+/// otherwise from the start of the `path`.
+///
+/// Host names aren't case sensitive, so a bucket from the `host` is lower-cased.
+fn bucket_name<'a>(
+    host: Option<&'a str>,
+    base_domain: Option<&str>,
+    path: &'a str,
+) -> (Cow<'a, str>, &'a str) {
     assert!(path.starts_with('/'));
     let path = &path[1..];
+
+    if let (Some(host), Some(base_domain)) = (host, base_domain) {
+        // the port, if any, doesn't matter
+        let host = match host.rfind(':') {
+            Some(colon) => &host[..colon],
+            None => host,
+        };
+        let suffix = host.len().saturating_sub(base_domain.len() + 1);
+        if suffix > 0
+            && host.is_char_boundary(suffix)
+            && host[suffix..].starts_with('.')
+            && host[suffix + 1..].eq_ignore_ascii_case(base_domain)
+        {
+            return (Cow::Owned(host[..suffix].to_ascii_lowercase()), path);
+        }
+    }
+
     match path.find('/') {
-        Some(slash) => (&path[..slash], &path[slash..]),
-        None => (path, ""),
+        Some(slash) => (Cow::Borrowed(&path[..slash]), &path[slash + 1..]),
+        None => (Cow::Borrowed(path), ""),
     }
 }
 
+/// Serve the request, rendering any `S3Error` as the error document S3 clients expect.
+///
+/// Other failures are only returned if they're `error::fatal`; the rest are logged,
+/// and reported to the client as an `InternalError`.
 pub async fn handle(req: Request<Body>, state: CopyState) -> Result<SimpleResponse, Error> {
-    let not_found = SimpleResponse {
-        status: 404,
-        body: Body::empty(),
-    };
+    let request_id = format!("{:016X}", rand::random::<u64>());
+    let resource = hyp::path(&req).to_string();
+    let head = hyper::Method::HEAD == req.method();
 
-    let not_reasonable = SimpleResponse {
-        status: 400,
-        body: Body::empty(),
+    let mut response = match route(req, state, &request_id).await {
+        Ok(response) => response,
+        Err(e) => match e.downcast::<S3Error>() {
+            Ok(refusal) => error_response(refusal, &resource, &request_id, head),
+            Err(e) => {
+                if error::fatal(&e, Path::new(".")).await {
+                    return Err(e);
+                }
+                log::error!("{} failed: {:?}: {:?}", request_id, resource, e);
+                error_response(S3Error::InternalError, &resource, &request_id, head)
+            }
+        },
     };
 
+    response
+        .headers
+        .insert("x-amz-request-id".to_string(), request_id);
+    Ok(response)
+}
+
+fn error_response(
+    refusal: S3Error,
+    resource: &str,
+    request_id: &str,
+    head: bool,
+) -> SimpleResponse {
+    log::debug!("refusing {:?}: {:?}", resource, refusal);
+
+    // responses to HEAD requests can't have a body
+    if head {
+        return SimpleResponse::empty(refusal.status());
+    }
+
+    let mut response = SimpleResponse::xml(refusal.to_xml(resource, request_id));
+    response.status = refusal.status();
+    response
+}
+
+async fn route(
+    req: Request<Body>,
+    state: CopyState,
+    request_id: &str,
+) -> Result<SimpleResponse, Error> {
     let method = match hyp::method(req.method()) {
         Some(method) => method,
-        _ => {
-            return Ok(SimpleResponse {
-                status: 405,
-                body: Body::empty(),
-            })
-        }
+        _ => return Err(S3Error::MethodNotAllowed.into()),
     };
 
-    let headers = hyp::headers(&req)?;
-    let (user, headers) = match sig::validate(
-        &format!("{}", req.uri()),
-        |access| state.master.secret_key_for(access),
-        Utc::now(),
-        headers,
-        HttpRequestMethod::PUT,
-    ) {
-        Validation::Invalid | Validation::Unsupported => {
-            return Ok(SimpleResponse {
-                status: 403,
-                body: Body::empty(),
-            })
-        }
-        Validation::Anonymous(headers) => (None, headers),
-        Validation::Valid(user, headers) => (Some(user), headers),
+    let headers = hyp::headers(&req).map_err(|_| S3Error::InvalidArgument)?;
+    let params = hyp::query_map(&req).map_err(|_| S3Error::InvalidArgument)?;
+
+    let signed_region = sig::signed_region(&headers, &params);
+
+    // presigned urls carry their signature in the query string
+    let validation = if params.contains_key("X-Amz-Signature") {
+        sig::validate_query(
+            req.method().as_str(),
+            hyp::path(&req),
+            &params,
+            state.regions,
+            |access| state.master.secret_key_for(access),
+            Utc::now(),
+            headers,
+        )
+    } else {
+        sig::validate(
+            req.method().as_str(),
+            hyp::path(&req),
+            &params,
+            state.regions,
+            |access| state.master.secret_key_for(access),
+            Utc::now(),
+            headers,
+        )
+    };
+
+    let (user, headers, signer) = match validation {
+        Validation::Invalid => return Err(S3Error::SignatureDoesNotMatch.into()),
+        Validation::Unsupported => return Err(S3Error::AccessDenied.into()),
+        Validation::Anonymous(headers) => (None, headers, None),
+        Validation::Valid(user, headers) => (Some(user), headers, None),
+        Validation::Chunked(user, headers, signer) => (Some(user), headers, Some(signer)),
     };
 
+    let payload = sig::payload(&headers, signer)?;
+
     log::info!("{:?}, {:?}, {:?}", method, hyp::path(&req), headers);
 
-    let (bucket, path) = bucket_name(hyp::path(&req));
+    let (bucket, key) = bucket_name(hyp::host(&req), state.base_domain, hyp::path(&req));
+
+    let key = match hyp::decode(key) {
+        Ok(key) => key,
+        Err(_) => return Err(S3Error::InvalidArgument.into()),
+    };
+
+    let grants = role_policy(state, user.as_deref()).await?;
+    if let Some((action, resource)) = action(method, &bucket, &key, &params) {
+        let anonymous = user.is_none();
+        let allowed = grants.allows(action, &bucket, resource)
+            || (anonymous && public(&bucket, action).await?);
+        if !allowed {
+            log::debug!("denied {:?} on {:?} / {:?}", action, bucket, resource);
+            return Err(S3Error::AccessDenied.into());
+        }
+    }
+
+    if bucket.is_empty() {
+        return match method {
+            SimpleMethod::Get => {
+                let mut buckets = bucket::list(Path::new(".")).await?;
+                buckets.retain(|(name, _)| grants.mentions(name.as_str()));
+                let owner = user.unwrap_or_default();
+                Ok(SimpleResponse::xml(list::list_buckets(&owner, &buckets)))
+            }
+            _ => Err(S3Error::MethodNotAllowed.into()),
+        };
+    }
 
     let bucket = match bucket::Name::from(bucket) {
         Some(bucket) => bucket,
-        None => return Ok(not_reasonable),
+        None => return Err(S3Error::InvalidBucketName.into()),
     };
 
     let config = bucket::get_config(Path::new("."), &bucket).await?;
 
+    // any region we serve is fine until we know the bucket's, which S3 insists on
+    if let (Some(signed), Some(config)) = (&signed_region, &config) {
+        if signed != config.region().unwrap_or(state.regions[0].as_str()) {
+            return Err(S3Error::AuthorizationHeaderMalformed.into());
+        }
+    }
+
+    if key.is_empty() {
+        return bucket_request(req, state, method, bucket, config, payload, &grants).await;
+    }
+
+    let versioning = match config {
+        Some(config) => config.versioning(),
+        None => return Err(S3Error::NoSuchBucket.into()),
+    };
+
+    let store = bucket.dir(Path::new("."));
+
+    if params.contains_key("uploads") || params.contains_key("uploadId") {
+        return upload_request(req, state, &bucket, &key, headers, payload, versioning).await;
+    }
+
+    let version_id = match params.get("versionId").map(|id| id.parse::<usize>()) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return Err(S3Error::InvalidArgument.into()),
+        None => None,
+    };
+
+    // a specific version which is missing is different to a missing key
+    let missing = match version_id {
+        Some(_) => S3Error::NoSuchVersion,
+        None => S3Error::NoSuchKey,
+    };
+
     match method {
+        SimpleMethod::Get if params.contains_key("attributes") => {
+            let (id, version) = match dir::head(&store, &key, version_id).await? {
+                Some(found) => found,
+                None => return Err(missing.into()),
+            };
+            let requested = req
+                .headers()
+                .get("x-amz-object-attributes")
+                .and_then(|v| v.to_str().ok())
+                .ok_or(S3Error::InvalidArgument)?;
+
+            let mut response = SimpleResponse::xml(object_attributes(&version, requested)?);
+            response.headers.insert(
+                "last-modified".to_string(),
+                version
+                    .modified()
+                    .format("%a, %d %b %Y %H:%M:%S GMT")
+                    .to_string(),
+            );
+            version_header(&mut response.headers, versioning, id);
+            Ok(response)
+        }
         SimpleMethod::Get => {
-            let (_meta, file) = match dir::get(Path::new("."), &path).await? {
+            let (id, version, file) = match dir::get(&store, &key, version_id).await? {
                 Some(parts) => parts,
-                None => return Ok(not_found),
+                None => return Err(missing.into()),
             };
+            match Conditions::from_headers(req.headers()).check_read(&version) {
+                cond::Outcome::Proceed => (),
+                cond::Outcome::NotModified => return not_modified(&version),
+                cond::Outcome::PreconditionFailed => return Err(S3Error::PreconditionFailed.into()),
+            }
+
+            let mut headers = object_headers(&version)?;
+            headers.insert("accept-ranges".to_string(), "bytes".to_string());
+            version_header(&mut headers, versioning, id);
+
+            let length = version.content_length();
+            let ranges = match req.headers().get("range").and_then(|v| v.to_str().ok()) {
+                Some(range) => range::parse(range, length),
+                None => Ranges::Whole,
+            };
+
             let (sender, body) = Body::channel();
-            tokio::spawn(super::hyper_files::stream_unpack(file, sender));
-            Ok(SimpleResponse { status: 200, body })
+
+            let parts = match ranges {
+                Ranges::Whole => {
+                    send_in_background(hyper_files::stream_unpack(file, sender));
+                    return Ok(SimpleResponse {
+                        status: 200,
+                        headers,
+                        body,
+                    });
+                }
+                Ranges::Unsatisfiable => {
+                    let refusal = S3Error::InvalidRange;
+                    let mut response = error_response(refusal, hyp::path(&req), request_id, false);
+                    response
+                        .headers
+                        .insert("content-range".to_string(), format!("bytes */{}", length));
+                    return Ok(response);
+                }
+                Ranges::Parts(parts) => parts,
+            };
+
+            let (parts, trailer) = if 1 == parts.len() {
+                let (start, end) = parts[0];
+                headers.insert(
+                    "content-range".to_string(),
+                    range::content_range(start, end, length),
+                );
+                headers.insert("content-length".to_string(), (end - start + 1).to_string());
+                let part = range::Part {
+                    preamble: Vec::new(),
+                    start,
+                    len: end - start + 1,
+                };
+                (vec![part], Vec::new())
+            } else {
+                let content_type = headers
+                    .get("content-type")
+                    .expect("always populated")
+                    .to_string();
+                let multipart = range::Multipart::new(&parts, &content_type, length);
+                headers.insert(
+                    "content-type".to_string(),
+                    format!("multipart/byteranges; boundary={}", multipart.boundary),
+                );
+                headers.insert(
+                    "content-length".to_string(),
+                    multipart.content_length().to_string(),
+                );
+                (multipart.parts, multipart.trailer)
+            };
+
+            send_in_background(hyper_files::stream_unpack_ranges(
+                file,
+                sender,
+                version.frames().clone(),
+                parts,
+                trailer,
+            ));
+
+            Ok(SimpleResponse {
+                status: 206,
+                headers,
+                body,
+            })
         }
         SimpleMethod::Put => {
-            let mut temp = super::temp::NamedTempFile::new_in(".").await?;
-            // BORROW CHECKER
-            let path = path.to_string();
-            let content = super::hyper_files::stream_pack(req.into_body(), &mut temp).await?;
+            if req.headers().contains_key("x-amz-copy-source") {
+                return copy_object(&req, state, &bucket, &key, headers, versioning, &grants).await;
+            }
+
+            let conditions = Conditions::from_headers(req.headers());
+            let mut headers = headers;
+            if let sig::Payload::Chunked { .. } = payload {
+                chunked::strip_encoding(&mut headers);
+            }
+
+            let expected = checksum::Expected::from_headers(req.headers())?;
+            let mut temp = super::temp::NamedTempFile::new_in(&store).await?;
+            let content =
+                super::hyper_files::stream_pack(req.into_body(), &mut temp, payload, &expected)
+                    .await?;
             let temp = temp.into_temp_path();
 
-            dir::store(
-                Path::new("."),
-                &tokio::sync::Mutex::new(()),
-                &path,
+            let written = dir::store(
+                &store,
+                state.meta_lock,
+                &key,
                 headers,
                 Intermediate { temp, content },
+                versioning,
+                |current| conditions.check_write(current),
             )
             .await?;
 
+            let id = match written {
+                Some((id, _)) => id,
+                None => return Err(S3Error::PreconditionFailed.into()),
+            };
+
+            let mut response = SimpleResponse::empty(202);
+            version_header(&mut response.headers, versioning, id);
+            Ok(response)
+        }
+        SimpleMethod::Delete => {
+            let mut response = SimpleResponse::empty(204);
+            match version_id {
+                // deleting a version which doesn't exist is a success
+                Some(id) => {
+                    dir::purge(&store, state.meta_lock, &key, id).await?;
+                    version_header(&mut response.headers, versioning, id);
+                }
+                None => dir::delete(&store, state.meta_lock, &key, versioning).await?,
+            }
+            Ok(response)
+        }
+        SimpleMethod::Head => {
+            let (id, version) = match dir::head(&store, &key, version_id).await? {
+                Some(found) => found,
+                None => return Err(missing.into()),
+            };
+            match Conditions::from_headers(req.headers()).check_read(&version) {
+                cond::Outcome::Proceed => (),
+                cond::Outcome::NotModified => return not_modified(&version),
+                cond::Outcome::PreconditionFailed => return Err(S3Error::PreconditionFailed.into()),
+            }
+            let mut headers = object_headers(&version)?;
+            version_header(&mut headers, versioning, id);
             Ok(SimpleResponse {
-                status: 202,
+                status: 200,
+                headers,
                 body: Body::empty(),
             })
         }
-        other => bail!("not implemented: {:?}", other),
+        SimpleMethod::Post => Err(S3Error::NotImplemented.into()),
+    }
+}
+
+/// Stream a response body after the headers have gone, when failures can only be logged.
+fn send_in_background(sending: impl Future<Output = Result<(), Error>> + Send + 'static) {
+    tokio::spawn(async move {
+        if let Err(e) = sending.await {
+            log::error!("sending body failed: {:?}", e);
+        }
+    });
+}
+
+/// The policy of the role the `access_key` was issued for; anonymous requests get an empty one.
+async fn role_policy(state: CopyState, access_key: Option<&str>) -> Result<roles::Policy, Error> {
+    let access_key = match access_key {
+        Some(access_key) => access_key,
+        None => return Ok(roles::Policy::default()),
+    };
+
+    let role = state
+        .master
+        .parse_access(access_key)
+        .map_err(|_| S3Error::InvalidAccessKeyId)?;
+
+    // keys issued before roles had policies have none stored, and can do anything, as they
+    // always could; every later key was issued with one. Restrict, or revoke, a role by
+    // writing its policy, as removing it would allow everything
+    Ok(roles::get(Path::new("."), role)
+        .await?
+        .unwrap_or_else(roles::Policy::allow_all))
+}
+
+/// Whether anonymous requests may `action` the `bucket`; they may only read public buckets.
+async fn public(bucket: &str, action: Action) -> Result<bool, Error> {
+    match action {
+        Action::GetObject | Action::ListBucket => (),
+        _ => return Ok(false),
+    }
+
+    let bucket = match bucket::Name::from(bucket) {
+        Some(bucket) => bucket,
+        None => return Ok(false),
+    };
+
+    Ok(match bucket::get_config(Path::new("."), &bucket).await? {
+        Some(config) => config.public_read(),
+        None => false,
+    })
+}
+
+/// Whether the query names something of the bucket's, rather than the bucket itself.
+///
+/// Presigned requests carry their signature in `X-Amz-*` parameters, which don't count.
+fn subresource(params: &HashMap<String, String>) -> bool {
+    params.keys().any(|param| !param.starts_with("X-Amz-"))
+}
+
+/// Whether a canned `x-amz-acl` makes a bucket public; anonymous writes are never allowed.
+fn public_acl(acl: Option<&str>) -> Result<bool, S3Error> {
+    match acl {
+        None | Some("private") => Ok(false),
+        Some("public-read") => Ok(true),
+        Some("public-read-write") => Err(S3Error::AccessDenied),
+        Some(_) => Err(S3Error::NotImplemented),
+    }
+}
+
+/// What the request needs its role to allow, and on which key, or listing prefix.
+///
+/// `None` for bucket POSTs, i.e. DeleteObjects and browser uploads, which check each key.
+fn action<'a>(
+    method: SimpleMethod,
+    bucket: &str,
+    key: &'a str,
+    params: &'a HashMap<String, String>,
+) -> Option<(Action, &'a str)> {
+    let configuring = ["acl", "location", "versioning", "lifecycle"]
+        .iter()
+        .any(|param| params.contains_key(*param));
+
+    let action = match method {
+        _ if bucket.is_empty() => Action::ListAllMyBuckets,
+        SimpleMethod::Get | SimpleMethod::Head if key.is_empty() && configuring => {
+            Action::GetBucketConfiguration
+        }
+        SimpleMethod::Get | SimpleMethod::Head if key.is_empty() => {
+            let prefix = params.get("prefix").map(|p| p.as_str()).unwrap_or("");
+            return Some((Action::ListBucket, prefix));
+        }
+        SimpleMethod::Put | SimpleMethod::Delete if key.is_empty() && configuring => {
+            Action::PutBucketConfiguration
+        }
+        SimpleMethod::Put if key.is_empty() => Action::CreateBucket,
+        SimpleMethod::Delete if key.is_empty() => Action::DeleteBucket,
+        SimpleMethod::Post if key.is_empty() => return None,
+        // including listing, and aborting, multipart uploads
+        _ if params.contains_key("uploads") || params.contains_key("uploadId") => Action::PutObject,
+        SimpleMethod::Get | SimpleMethod::Head => Action::GetObject,
+        SimpleMethod::Put | SimpleMethod::Post => Action::PutObject,
+        SimpleMethod::Delete => Action::DeleteObject,
+    };
+
+    Some((action, key))
+}
+
+/// Headers the uploader set, which we store and send back.
+const STORED_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "content-type",
+    "expires",
+];
+
+/// The headers describing a stored version, as sent on GET and HEAD.
+fn object_headers(version: &FileVersion) -> Result<HashMap<String, String>, Error> {
+    let mut headers = HashMap::with_capacity(8);
+    headers.insert(
+        "content-type".to_string(),
+        "binary/octet-stream".to_string(),
+    );
+    headers.insert(
+        "content-length".to_string(),
+        version.content_length().to_string(),
+    );
+    headers.insert("etag".to_string(), version.etag()?);
+    headers.insert(
+        "last-modified".to_string(),
+        version
+            .modified()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string(),
+    );
+    for (k, v) in version.meta() {
+        if k.starts_with("x-amz-meta-") || STORED_HEADERS.contains(&k.as_str()) {
+            headers.insert(k.to_string(), v.to_string());
+        }
+    }
+    Ok(headers)
+}
+
+/// Render a GetObjectAttributes response, with the comma separated `requested` attributes.
+fn object_attributes(version: &FileVersion, requested: &str) -> Result<String, Error> {
+    let mut xml = Xml::new("GetObjectAttributesResponse");
+    for attribute in requested.split(',').map(|a| a.trim()) {
+        match attribute {
+            "ETag" => xml.text("ETag", version.etag()?.trim_matches('"')),
+            "Checksum" if version.checksums().is_empty() => (),
+            "Checksum" => version.checksums().write_xml(&mut xml),
+            "ObjectParts" => {
+                if let Some(parts) = version.parts() {
+                    xml.open("ObjectParts");
+                    xml.text("PartsCount", parts);
+                    xml.close("ObjectParts");
+                }
+            }
+            "StorageClass" => xml.text("StorageClass", "STANDARD"),
+            "ObjectSize" => xml.text("ObjectSize", version.content_length()),
+            _ => return Err(S3Error::InvalidArgument.into()),
+        }
+    }
+    Ok(xml.finish())
+}
+
+/// Tell versioning-aware clients which version they're dealing with.
+fn version_header(headers: &mut HashMap<String, String>, versioning: VersioningPolicy, id: usize) {
+    if VersioningPolicy::Off != versioning {
+        headers.insert("x-amz-version-id".to_string(), id.to_string());
+    }
+}
+
+fn not_modified(version: &FileVersion) -> Result<SimpleResponse, Error> {
+    let mut response = SimpleResponse::empty(304);
+    let mut headers = object_headers(version)?;
+    for name in &["etag", "last-modified"] {
+        if let Some(value) = headers.remove(*name) {
+            response.headers.insert(name.to_string(), value);
+        }
+    }
+    Ok(response)
+}
+
+/// The bucket, key and version of an `x-amz-copy-source`, like `/bucket/some%20key?versionId=3`.
+fn copy_source(header: &str) -> Option<(bucket::Name, String, Option<usize>)> {
+    let marker = "?versionId=";
+    let (path, version) = match header.find(marker) {
+        Some(query) => (
+            &header[..query],
+            Some(header[query + marker.len()..].parse().ok()?),
+        ),
+        None => (header, None),
+    };
+    let path = hyp::decode(path).ok()?;
+    let path = path.trim_start_matches('/');
+    let slash = path.find('/')?;
+    let bucket = bucket::Name::from(&path[..slash])?;
+    let key = &path[slash + 1..];
+    if key.is_empty() {
+        return None;
+    }
+    Some((bucket, key.to_string(), version))
+}
+
+/// Store an existing version under `key`, without unpacking it.
+async fn copy_object(
+    req: &Request<Body>,
+    state: CopyState,
+    bucket: &bucket::Name,
+    key: &str,
+    headers: HashMap<String, String>,
+    versioning: VersioningPolicy,
+    grants: &roles::Policy,
+) -> Result<SimpleResponse, Error> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+
+    let (source_bucket, source_key, source_version) =
+        match header("x-amz-copy-source").and_then(copy_source) {
+            Some(source) => source,
+            None => return Err(S3Error::InvalidArgument.into()),
+        };
+
+    grants.check(Action::GetObject, source_bucket.as_str(), &source_key)?;
+
+    let replace = match header("x-amz-metadata-directive") {
+        None | Some("COPY") => false,
+        Some("REPLACE") => true,
+        Some(_) => return Err(S3Error::InvalidArgument.into()),
+    };
+
+    // copying an object onto itself is only useful for changing its metadata
+    if !replace && source_bucket == *bucket && source_key == key && source_version.is_none() {
+        return Err(S3Error::InvalidRequest.into());
+    }
+
+    if bucket::get_config(Path::new("."), &source_bucket)
+        .await?
+        .is_none()
+    {
+        return Err(S3Error::NoSuchBucket.into());
+    }
+
+    let missing = match source_version {
+        Some(_) => S3Error::NoSuchVersion,
+        None => S3Error::NoSuchKey,
+    };
+
+    let source_store = source_bucket.dir(Path::new("."));
+    let store = bucket.dir(Path::new("."));
+
+    let (version, intermediate) =
+        match dir::link_version(&source_store, &source_key, source_version, &store).await? {
+            Some(linked) => linked,
+            None => return Err(missing.into()),
+        };
+
+    match Conditions::from_copy_source_headers(req.headers()).check_read(&version) {
+        cond::Outcome::Proceed => (),
+        _ => return Err(S3Error::PreconditionFailed.into()),
+    }
+
+    let meta = if replace {
+        headers
+    } else {
+        version.meta().clone()
+    };
+
+    let conditions = Conditions::from_headers(req.headers());
+    let written = dir::store(
+        &store,
+        state.meta_lock,
+        key,
+        meta,
+        intermediate,
+        versioning,
+        |current| conditions.check_write(current),
+    )
+    .await?;
+
+    let (id, stored) = match written {
+        Some(written) => written,
+        None => return Err(S3Error::PreconditionFailed.into()),
+    };
+
+    let mut xml = Xml::new("CopyObjectResult");
+    xml.text(
+        "LastModified",
+        stored.modified().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+    );
+    xml.text("ETag", version.etag()?);
+    let mut response = SimpleResponse::xml(xml.finish());
+    version_header(&mut response.headers, versioning, id);
+    Ok(response)
+}
+
+/// Store the file from a browser's POST form, if the form's signed policy allows it.
+async fn post_object(
+    req: Request<Body>,
+    state: CopyState,
+    bucket: &bucket::Name,
+    config: &BucketConfig,
+    mut decoder: form::Decoder,
+) -> Result<SimpleResponse, Error> {
+    let versioning = config.versioning();
+    let region = config.region().unwrap_or(state.regions[0].as_str());
+    let mut body = req.into_body();
+
+    // the fields, which the policy covers, all arrive before the file
+    let mut first = Vec::new();
+    let fields = loop {
+        if let Some(fields) = decoder.fields() {
+            break fields.clone();
+        }
+        match body.data().await {
+            Some(data) => first.extend(decoder.push(&data?)?),
+            None => return Err(S3Error::InvalidArgument.into()),
+        }
+    };
+
+    let (user, policy) = post::validate(
+        bucket.as_str(),
+        &fields,
+        &[region.to_string()],
+        |access| state.master.secret_key_for(access),
+        Utc::now(),
+    )?;
+
+    let key = match fields.get("key") {
+        Some(key) if !key.is_empty() => key.replace("${filename}", decoder.filename()),
+        _ => return Err(S3Error::InvalidArgument.into()),
+    };
+
+    role_policy(state, Some(&user))
+        .await?
+        .check(Action::PutObject, bucket.as_str(), &key)?;
+
+    let meta: HashMap<String, String> = fields
+        .iter()
+        .filter(|(k, _)| k.starts_with("x-amz-meta-") || STORED_HEADERS.contains(&k.as_str()))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    let (min, max) = policy.length_range();
+    let store = bucket.dir(Path::new("."));
+    let mut temp = super::temp::NamedTempFile::new_in(&store).await?;
+    let expected = checksum::Expected::default();
+    let (sender, file) = Body::channel();
+    let (content, forwarded) = futures::join!(
+        super::hyper_files::stream_pack(file, &mut temp, sig::Payload::Unsigned, &expected),
+        form::forward(body, decoder, first, sender, max)
+    );
+
+    // the file is cut short if the form was broken, which only the forwarding knows about
+    let content = content?;
+    forwarded?;
+
+    if content.length < min {
+        return Err(S3Error::EntityTooSmall.into());
+    }
+
+    let etag = content.etag()?;
+    let temp = temp.into_temp_path();
+
+    let written = dir::store(
+        &store,
+        state.meta_lock,
+        &key,
+        meta,
+        Intermediate { temp, content },
+        versioning,
+        |_| true,
+    )
+    .await?;
+
+    let id = match written {
+        Some((id, _)) => id,
+        None => return Err(S3Error::PreconditionFailed.into()),
+    };
+
+    let mut response = if let Some(redirect) = fields.get("success_action_redirect") {
+        let separator = if redirect.contains('?') { '&' } else { '?' };
+        let mut response = SimpleResponse::empty(303);
+        response.headers.insert(
+            "location".to_string(),
+            format!(
+                "{}{}bucket={}&key={}&etag={}",
+                redirect,
+                separator,
+                sig::encode(bucket.as_str()),
+                sig::encode(&key),
+                sig::encode(&etag)
+            ),
+        );
+        response
+    } else {
+        match fields.get("success_action_status").map(|s| s.as_str()) {
+            Some("200") => SimpleResponse::empty(200),
+            Some("201") => {
+                let xml = post::post_response(bucket.as_str(), &key, &etag);
+                let mut response = SimpleResponse::xml(xml);
+                response.status = 201;
+                response
+            }
+            _ => SimpleResponse::empty(204),
+        }
+    };
+
+    response.headers.insert("etag".to_string(), etag);
+    version_header(&mut response.headers, versioning, id);
+    Ok(response)
+}
+
+/// The multipart upload operations, on `?uploads` and `?uploadId=`.
+async fn upload_request(
+    req: Request<Body>,
+    state: CopyState,
+    bucket: &bucket::Name,
+    key: &str,
+    headers: HashMap<String, String>,
+    payload: sig::Payload,
+    versioning: VersioningPolicy,
+) -> Result<SimpleResponse, Error> {
+    let method = hyp::method(req.method()).ok_or(S3Error::MethodNotAllowed)?;
+    let params = hyp::query_map(&req).map_err(|_| S3Error::InvalidArgument)?;
+    let store = bucket.dir(Path::new("."));
+    let status = SimpleResponse::empty;
+    let refused = |refusal: multipart::Refusal| -> Error { S3Error::from(refusal).into() };
+
+    let upload_id = match (method, params.get("uploadId")) {
+        (_, Some(upload_id)) => upload_id,
+        (SimpleMethod::Post, None) => {
+            let xml = multipart::create(&store, bucket.as_str(), key, headers).await?;
+            return Ok(SimpleResponse::xml(xml));
+        }
+        (_, None) => return Err(S3Error::MethodNotAllowed.into()),
+    };
+
+    match method {
+        // UploadPartCopy; the body is empty, so must not be stored as the part
+        SimpleMethod::Put if req.headers().contains_key("x-amz-copy-source") => {
+            Err(S3Error::NotImplemented.into())
+        }
+        SimpleMethod::Put => {
+            let part_number = match params.get("partNumber") {
+                Some(part_number) => part_number,
+                None => return Err(S3Error::InvalidArgument.into()),
+            };
+            let expected = checksum::Expected::from_headers(req.headers())?;
+            let body = req.into_body();
+            match multipart::upload_part(
+                &store,
+                key,
+                upload_id,
+                part_number,
+                body,
+                payload,
+                &expected,
+            )
+            .await?
+            {
+                Ok(etag) => {
+                    let mut response = status(200);
+                    response.headers.insert("etag".to_string(), etag);
+                    Ok(response)
+                }
+                Err(refusal) => Err(refused(refusal)),
+            }
+        }
+        SimpleMethod::Post => {
+            let conditions = Conditions::from_headers(req.headers());
+            let body =
+                hyper_files::payload_bytes(req.into_body(), payload, 2 * 1024 * 1024).await?;
+            let body = match body {
+                Some(body) => body,
+                None => return Err(S3Error::MaxMessageLengthExceeded.into()),
+            };
+            let completion = match multipart::Completion::from_xml(&body) {
+                Some(completion) => completion,
+                None => return Err(S3Error::MalformedXML.into()),
+            };
+
+            let (meta, intermediate) =
+                match multipart::complete(&store, key, upload_id, completion).await? {
+                    Ok(assembled) => assembled,
+                    Err(refusal) => return Err(refused(refusal)),
+                };
+            let etag = intermediate.content.etag()?;
+
+            let written = dir::store(
+                &store,
+                state.meta_lock,
+                key,
+                meta,
+                intermediate,
+                versioning,
+                |current| conditions.check_write(current),
+            )
+            .await?;
+
+            let id = match written {
+                Some((id, _)) => id,
+                None => return Err(S3Error::PreconditionFailed.into()),
+            };
+
+            // someone else may have already completed or aborted it; that's fine, and the object
+            // is written, so failing to clean up the parts mustn't fail the request
+            if let Err(e) = multipart::abort(&store, key, upload_id).await {
+                log::warn!("leaving parts of {:?} for {:?}: {:?}", upload_id, key, e);
+            }
+
+            let mut response =
+                SimpleResponse::xml(multipart::complete_result(bucket.as_str(), key, &etag));
+            version_header(&mut response.headers, versioning, id);
+            Ok(response)
+        }
+        SimpleMethod::Delete => match multipart::abort(&store, key, upload_id).await? {
+            Ok(()) => Ok(status(204)),
+            Err(refusal) => Err(refused(refusal)),
+        },
+        SimpleMethod::Get => {
+            match multipart::list_parts(&store, bucket.as_str(), key, upload_id, &params).await? {
+                Ok(xml) => Ok(SimpleResponse::xml(xml)),
+                Err(refusal) => Err(refused(refusal)),
+            }
+        }
+        SimpleMethod::Head => Err(S3Error::MethodNotAllowed.into()),
+    }
+}
+
+async fn bucket_request(
+    req: Request<Body>,
+    state: CopyState,
+    method: SimpleMethod,
+    bucket: bucket::Name,
+    config: Option<BucketConfig>,
+    payload: sig::Payload,
+    grants: &roles::Policy,
+) -> Result<SimpleResponse, Error> {
+    let store = bucket.dir(Path::new("."));
+
+    let status = SimpleResponse::empty;
+
+    let params = hyp::query_map(&req).map_err(|_| S3Error::InvalidArgument)?;
+
+    // browsers upload objects by POSTing a form to the bucket
+    let form = req
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .and_then(form::Decoder::new);
+
+    match (method, config) {
+        (SimpleMethod::Put, config) if params.contains_key("versioning") => {
+            if config.is_none() {
+                return Err(S3Error::NoSuchBucket.into());
+            }
+
+            let body = hyper_files::payload_bytes(req.into_body(), payload, 64 * 1024).await?;
+            let body = match body {
+                Some(body) => body,
+                None => return Err(S3Error::MaxMessageLengthExceeded.into()),
+            };
+
+            let versioning = match versions::from_xml(&body) {
+                Some(versioning) => versioning,
+                None => return Err(S3Error::MalformedXML.into()),
+            };
+
+            let change = |config: &mut BucketConfig| config.set_versioning(versioning);
+            bucket::update_config(Path::new("."), state.meta_lock, &bucket, change).await?;
+            Ok(status(200))
+        }
+        (SimpleMethod::Put, config) if params.contains_key("lifecycle") => {
+            if config.is_none() {
+                return Err(S3Error::NoSuchBucket.into());
+            }
+
+            let body =
+                hyper_files::payload_bytes(req.into_body(), payload, 2 * 1024 * 1024).await?;
+            let body = match body {
+                Some(body) => body,
+                None => return Err(S3Error::MaxMessageLengthExceeded.into()),
+            };
+
+            let rules = match lifecycle::from_xml(&body) {
+                Some(rules) => rules,
+                None => return Err(S3Error::MalformedXML.into()),
+            };
+
+            let change = |config: &mut BucketConfig| config.set_rules(rules);
+            bucket::update_config(Path::new("."), state.meta_lock, &bucket, change).await?;
+            Ok(status(200))
+        }
+        (SimpleMethod::Put, config) if params.contains_key("acl") => {
+            if config.is_none() {
+                return Err(S3Error::NoSuchBucket.into());
+            }
+
+            // an AccessControlPolicy body, granting to individual users, isn't supported
+            let acl = match req.headers().get("x-amz-acl") {
+                Some(acl) => acl.to_str().ok(),
+                None => return Err(S3Error::NotImplemented.into()),
+            };
+
+            let public_read = public_acl(acl)?;
+            let change = |config: &mut BucketConfig| config.set_public_read(public_read);
+            bucket::update_config(Path::new("."), state.meta_lock, &bucket, change).await?;
+            Ok(status(200))
+        }
+        // e.g. cors, tagging or policy, which would otherwise create the bucket
+        (SimpleMethod::Put, _) if subresource(&params) => Err(S3Error::NotImplemented.into()),
+        (SimpleMethod::Put, Some(_)) => Ok(status(200)),
+        (SimpleMethod::Put, None) => {
+            let acl = req.headers().get("x-amz-acl").and_then(|v| v.to_str().ok());
+            let public_read = public_acl(acl)?;
+
+            let body = hyper_files::payload_bytes(req.into_body(), payload, 64 * 1024).await?;
+            let body = match body {
+                Some(body) => body,
+                None => return Err(S3Error::MaxMessageLengthExceeded.into()),
+            };
+
+            let region = match bucket::location_from_xml(&body) {
+                Some(Some(region)) => region,
+                Some(None) => state.regions[0].to_string(),
+                None => return Err(S3Error::MalformedXML.into()),
+            };
+
+            if !state.regions.contains(&region) {
+                return Err(S3Error::InvalidLocationConstraint.into());
+            }
+
+            let mut config = BucketConfig::default();
+            config.set_region(region);
+            config.set_public_read(public_read);
+            bucket::put_config(Path::new("."), &bucket, &config).await?;
+            Ok(status(200))
+        }
+        (_, None) => Err(S3Error::NoSuchBucket.into()),
+        (SimpleMethod::Head, Some(_)) => Ok(status(200)),
+        (SimpleMethod::Delete, Some(_)) if params.contains_key("lifecycle") => {
+            let change = |config: &mut BucketConfig| config.set_rules(Vec::new());
+            bucket::update_config(Path::new("."), state.meta_lock, &bucket, change).await?;
+            Ok(status(204))
+        }
+        (SimpleMethod::Delete, Some(_)) if subresource(&params) => {
+            Err(S3Error::NotImplemented.into())
+        }
+        (SimpleMethod::Delete, Some(_)) => {
+            bucket::delete(Path::new("."), state.meta_lock, &bucket).await?;
+            Ok(status(204))
+        }
+        (SimpleMethod::Get, Some(config)) if params.contains_key("location") => {
+            let region = config.region().unwrap_or(state.regions[0].as_str());
+            Ok(SimpleResponse::xml(bucket::location(region)))
+        }
+        (SimpleMethod::Get, Some(config)) if params.contains_key("versioning") => Ok(
+            SimpleResponse::xml(versions::configuration(config.versioning())),
+        ),
+        (SimpleMethod::Get, Some(config)) if params.contains_key("lifecycle") => {
+            if config.rules().is_empty() {
+                return Err(S3Error::NoSuchLifecycleConfiguration.into());
+            }
+            Ok(SimpleResponse::xml(lifecycle::configuration(
+                config.rules(),
+            )))
+        }
+        (SimpleMethod::Get, Some(_)) if params.contains_key("versions") => {
+            let query = match versions::Query::from_params(&params) {
+                Ok(query) => query,
+                Err(_) => return Err(S3Error::InvalidArgument.into()),
+            };
+
+            let found = versions::versions(&store).await?;
+            Ok(SimpleResponse::xml(versions::list_versions(
+                bucket.as_str(),
+                found,
+                &query,
+            )))
+        }
+        (SimpleMethod::Get, Some(_)) => {
+            if params.get("list-type").map(|s| s.as_str()) != Some("2") {
+                log::warn!("not implemented: ListObjects (v1)");
+                return Err(S3Error::NotImplemented.into());
+            }
+
+            let query = match list::Query::from_params(&params) {
+                Ok(query) => query,
+                Err(_) => return Err(S3Error::InvalidArgument.into()),
+            };
+
+            let objects = list::objects(&store).await?;
+            match list::list_v2(bucket.as_str(), objects, &query) {
+                Ok(xml) => Ok(SimpleResponse::xml(xml)),
+                Err(_) => Err(S3Error::InvalidArgument.into()),
+            }
+        }
+        (SimpleMethod::Post, Some(config)) if form.is_some() => {
+            let decoder = form.expect("just checked");
+            post_object(req, state, &bucket, &config, decoder).await
+        }
+        (SimpleMethod::Post, Some(config)) => {
+            if !params.contains_key("delete") {
+                log::warn!("not implemented: bucket POST {:?}", params.keys());
+                return Err(S3Error::NotImplemented.into());
+            }
+
+            let body =
+                hyper_files::payload_bytes(req.into_body(), payload, 2 * 1024 * 1024).await?;
+            let body = match body {
+                Some(body) => body,
+                None => return Err(S3Error::MaxMessageLengthExceeded.into()),
+            };
+
+            let request = match delete::Request::from_xml(&body) {
+                Some(request) => request,
+                None => return Err(S3Error::MalformedXML.into()),
+            };
+
+            let allowed = |key: &str| grants.allows(Action::DeleteObject, bucket.as_str(), key);
+            let xml = delete::delete_objects(
+                &store,
+                state.meta_lock,
+                config.versioning(),
+                request,
+                allowed,
+            )
+            .await?;
+            Ok(SimpleResponse::xml(xml))
+        }
     }
 }
 
+#[test]
+fn copy_sources() {
+    let (bucket, key, version) = copy_source("/potato/an%20d/e").expect("valid");
+    assert_eq!("potato", bucket.as_str());
+    assert_eq!("an d/e", key);
+    assert_eq!(None, version);
+
+    let (_, key, version) = copy_source("potato/a?versionId=3").expect("valid");
+    assert_eq!("a", key);
+    assert_eq!(Some(3), version);
+
+    assert!(copy_source("/potato").is_none());
+    assert!(copy_source("/potato/").is_none());
+    assert!(copy_source("/potato/a?versionId=b").is_none());
+}
+
+#[test]
+fn actions() {
+    let params = |names: &[&str]| -> HashMap<String, String> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), String::new()))
+            .collect()
+    };
+    let none = params(&[]);
+
+    assert_eq!(
+        Some((Action::ListAllMyBuckets, "")),
+        action(SimpleMethod::Get, "", "", &none)
+    );
+    assert_eq!(
+        Some((Action::CreateBucket, "")),
+        action(SimpleMethod::Put, "potato", "", &none)
+    );
+    assert_eq!(
+        Some((Action::PutBucketConfiguration, "")),
+        action(SimpleMethod::Delete, "potato", "", &params(&["lifecycle"]))
+    );
+    assert_eq!(
+        Some((Action::GetBucketConfiguration, "")),
+        action(SimpleMethod::Get, "potato", "", &params(&["location"]))
+    );
+
+    let mut listing = params(&["list-type"]);
+    listing.insert("prefix".to_string(), "a/".to_string());
+    assert_eq!(
+        Some((Action::ListBucket, "a/")),
+        action(SimpleMethod::Get, "potato", "", &listing)
+    );
+
+    assert_eq!(None, action(SimpleMethod::Post, "potato", "", &none));
+    assert_eq!(
+        Some((Action::GetObject, "a")),
+        action(SimpleMethod::Head, "potato", "a", &none)
+    );
+    assert_eq!(
+        Some((Action::PutObject, "a")),
+        action(SimpleMethod::Delete, "potato", "a", &params(&["uploadId"]))
+    );
+    assert_eq!(
+        Some((Action::DeleteObject, "a")),
+        action(SimpleMethod::Delete, "potato", "a", &none)
+    );
+}
+
+#[test]
+fn subresources() {
+    let mut params = HashMap::new();
+    assert!(!subresource(&params));
+    params.insert("X-Amz-Signature".to_string(), "abc".to_string());
+    assert!(!subresource(&params));
+    params.insert("tagging".to_string(), String::new());
+    assert!(subresource(&params));
+}
+
+#[test]
+fn acls() {
+    assert_eq!(Ok(false), public_acl(None));
+    assert_eq!(Ok(false), public_acl(Some("private")));
+    assert_eq!(Ok(true), public_acl(Some("public-read")));
+    assert_eq!(
+        Err(S3Error::AccessDenied),
+        public_acl(Some("public-read-write"))
+    );
+    assert_eq!(
+        Err(S3Error::NotImplemented),
+        public_acl(Some("authenticated-read"))
+    );
+
+    let none = HashMap::new();
+    let acl = maplit::hashmap! { "acl".to_string() => String::new() };
+    assert_eq!(
+        Some((Action::PutBucketConfiguration, "")),
+        action(SimpleMethod::Put, "potato", "", &acl)
+    );
+    assert_eq!(
+        Some((Action::CreateBucket, "")),
+        action(SimpleMethod::Put, "potato", "", &none)
+    );
+}
+
 #[test]
 fn name() {
-    assert_eq!(("", ""), bucket_name("/"));
-    assert_eq!(("potato", ""), bucket_name("/potato"));
-    assert_eq!(("potato", "/"), bucket_name("/potato/"));
-    assert_eq!(("potato", "/an/d"), bucket_name("/potato/an/d"));
+    let split = |host, base, path| {
+        let (bucket, key) = bucket_name(host, base, path);
+        format!("{}/{}", bucket, key)
+    };
+    assert_eq!("/", split(None, None, "/"));
+    assert_eq!("potato/", split(None, None, "/potato"));
+    assert_eq!("potato/", split(None, None, "/potato/"));
+    assert_eq!("potato/an/d", split(None, None, "/potato/an/d"));
+
+    let base = Some("s3.example.com");
+    let vhost = |host, path| split(Some(host), base, path);
+    assert_eq!("potato/an/d", vhost("potato.s3.example.com", "/an/d"));
+    assert_eq!("potato/", vhost("potato.S3.Example.com:8202", "/"));
+    assert_eq!("potato/", vhost("Potato.s3.example.com", "/"));
+    assert_eq!("potato/an/", vhost("potato.s3.example.com", "/an/"));
+    assert_eq!("a.b/c", vhost("a.b.s3.example.com", "/c"));
+
+    // the base domain itself, or anywhere else, is path-style
+    assert_eq!("potato/an", vhost("s3.example.com", "/potato/an"));
+    assert_eq!("potato/an", vhost("localhost:8202", "/potato/an"));
+    assert_eq!("potato/an", vhost("xs3.example.com", "/potato/an"));
 }
@@ -0,0 +1,186 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure::Error;
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt as _;
+
+use super::error::S3Error;
+use super::users::RoleId;
+
+/// What a request does, as far as deciding whether a role may do it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    #[serde(rename = "*")]
+    All,
+    ListAllMyBuckets,
+    CreateBucket,
+    DeleteBucket,
+    /// Listing keys, or versions, with a prefix.
+    ListBucket,
+    /// Reading the bucket's location, versioning or lifecycle.
+    GetBucketConfiguration,
+    /// Changing the bucket's versioning or lifecycle.
+    PutBucketConfiguration,
+    /// Reading an object, including as the source of a copy.
+    GetObject,
+    /// Writing an object, by any kind of upload.
+    PutObject,
+    DeleteObject,
+}
+
+/// What the keys issued for a role may do; anything not allowed is denied.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    pub statements: Vec<Statement>,
+}
+
+/// Allows the `actions` on buckets matching `bucket`, for keys starting with `prefix`.
+///
+/// Bucket operations have an empty key, so are only allowed without a prefix; listings are
+/// for the prefix they ask for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Statement {
+    pub actions: Vec<Action>,
+    /// A bucket name, where `*` matches anything, like `logs-*`.
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+impl Policy {
+    /// Allows everything, as keys could do before they had roles, so the policy for a role
+    /// which has none stored.
+    pub fn allow_all() -> Policy {
+        Policy {
+            statements: vec![Statement {
+                actions: vec![Action::All],
+                bucket: "*".to_string(),
+                prefix: String::new(),
+            }],
+        }
+    }
+
+    pub fn allows(&self, action: Action, bucket: &str, key: &str) -> bool {
+        self.statements.iter().any(|statement| {
+            (statement.actions.contains(&Action::All) || statement.actions.contains(&action))
+                && matches(&statement.bucket, bucket)
+                && key.starts_with(&statement.prefix)
+        })
+    }
+
+    /// Whether anything is allowed on the `bucket`, so it's worth listing.
+    pub fn mentions(&self, bucket: &str) -> bool {
+        self.statements
+            .iter()
+            .any(|statement| !statement.actions.is_empty() && matches(&statement.bucket, bucket))
+    }
+
+    pub fn check(&self, action: Action, bucket: &str, key: &str) -> Result<(), S3Error> {
+        if !self.allows(action, bucket, key) {
+            log::debug!("denied {:?} on {:?} / {:?}", action, bucket, key);
+            return Err(S3Error::AccessDenied);
+        }
+        Ok(())
+    }
+}
+
+/// Whether the `value` matches the `pattern`, in which `*` matches any run of characters.
+fn matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().expect("split is never empty");
+    if !value.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &value[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // no wildcards
+        None => return rest.is_empty(),
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(found) => rest = &rest[found + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+fn path(storage: &Path, role: RoleId) -> PathBuf {
+    storage.join(".roles").join(format!("{}.json", role))
+}
+
+/// The role's policy, if one has been stored.
+pub async fn get(storage: &Path, role: RoleId) -> Result<Option<Policy>, Error> {
+    match fs::read(path(storage, role)).await {
+        Ok(c) => Ok(Some(serde_json::from_slice(&c)?)),
+        Err(ref e) if io::ErrorKind::NotFound == e.kind() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn put(storage: &Path, role: RoleId, policy: &Policy) -> Result<(), Error> {
+    let path = path(storage, role);
+    let dir = path.parent().expect("joined");
+    fs::create_dir_all(dir).await?;
+    let mut temp = super::temp::NamedTempFile::new_in(dir).await?;
+    let content = serde_json::to_vec(policy)?;
+    temp.write_all(&content).await?;
+    temp.into_temp_path()
+        .persist(path)
+        .await
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[test]
+fn matching() {
+    assert!(matches("*", ""));
+    assert!(matches("*", "potato"));
+    assert!(matches("potato", "potato"));
+    assert!(!matches("potato", "potatoes"));
+    assert!(matches("logs-*", "logs-2020"));
+    assert!(!matches("logs-*", "log"));
+    assert!(matches("*-logs", "web-logs"));
+    assert!(matches("a*b*c", "abc"));
+    assert!(matches("a*b*c", "a-b-b-c"));
+    assert!(!matches("a*b*c", "a-c-b"));
+    assert!(!matches("ab*ba", "aba"));
+}
+
+#[test]
+fn allowing() {
+    let policy: Policy = serde_json::from_str(
+        r#"{"statements": [
+            {"actions": ["GetObject", "ListBucket"], "bucket": "potato-*"},
+            {"actions": ["PutObject"], "bucket": "potato-uploads", "prefix": "incoming/"}
+        ]}"#,
+    )
+    .expect("valid");
+
+    assert!(policy.allows(Action::GetObject, "potato-a", "b"));
+    assert!(policy.allows(Action::ListBucket, "potato-a", ""));
+    assert!(!policy.allows(Action::GetObject, "carrot", "b"));
+    assert!(!policy.allows(Action::DeleteObject, "potato-a", "b"));
+    assert!(policy.allows(Action::PutObject, "potato-uploads", "incoming/a"));
+    assert!(!policy.allows(Action::PutObject, "potato-uploads", "a"));
+    assert!(!policy.allows(Action::PutObject, "potato-a", "incoming/a"));
+    assert_eq!(
+        Err(S3Error::AccessDenied),
+        policy.check(Action::CreateBucket, "potato-a", "")
+    );
+
+    assert!(policy.mentions("potato-uploads"));
+    assert!(!policy.mentions("carrot"));
+
+    assert!(!Policy::default().allows(Action::GetObject, "potato", "a"));
+    assert!(Policy::allow_all().allows(Action::DeleteBucket, "potato", ""));
+}
@@ -75,6 +75,21 @@ impl TempPath {
         TempPath { path }
     }
 
+    /// A new temporary name in `dir` for the existing file `original`.
+    pub async fn hard_link_in<P: AsRef<Path>>(original: &Path, dir: P) -> Result<TempPath, Error> {
+        let mut path = dir.as_ref().to_path_buf();
+        for _ in 0..256 {
+            let cand: u64 = rand::random();
+            path.push(format!(".{:x}.tmp", cand));
+            match fs::hard_link(original, &path).await {
+                Ok(()) => return Ok(TempPath::new(path)),
+                Err(ref e) if io::ErrorKind::AlreadyExists == e.kind() => assert!(path.pop()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(err_msg("gave up trying to create a temporary link"))
+    }
+
     pub async fn close(mut self) -> Result<(), Error> {
         let result = fs::remove_file(&self.path)
             .await
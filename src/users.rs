@@ -1,4 +1,13 @@
 use std::convert::TryInto;
+use std::fmt;
+
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+
+use crate::post;
+use crate::post::Condition;
+use crate::sig;
 
 #[derive(Copy, Clone)]
 pub struct MasterKey {
@@ -56,6 +65,108 @@ impl MasterKey {
         // ..should this check it's valid?
         pack(&mac(&self.key, access_key.as_bytes()))
     }
+
+    /// A presigned version of the (unencoded) `url`, like `http://localhost:8202/bucket/some key`,
+    /// which allows `method` without credentials until it `expires`.
+    pub fn presigned_url(
+        &self,
+        access_key: &str,
+        region: &str,
+        method: &str,
+        url: &str,
+        expires: Duration,
+        now: DateTime<Utc>,
+    ) -> String {
+        let authority = match url.find("://") {
+            Some(scheme) => scheme + 3,
+            None => 0,
+        };
+        let (endpoint, path) = match url[authority..].find('/') {
+            Some(path) => url.split_at(authority + path),
+            None => (url, "/"),
+        };
+        let host = &endpoint[authority..];
+
+        let path = sig::encode_path(path);
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/{}/s3", now.format("%Y%m%d"), region);
+        let credential = format!("{}/{}/aws4_request", access_key, scope);
+        let expires = expires.num_seconds().clamp(1, sig::MAX_EXPIRES).to_string();
+
+        let query = [
+            ("X-Amz-Algorithm", sig::ALGORITHM),
+            ("X-Amz-Credential", credential.as_str()),
+            ("X-Amz-Date", amz_date.as_str()),
+            ("X-Amz-Expires", expires.as_str()),
+            ("X-Amz-SignedHeaders", "host"),
+        ];
+
+        let canonical = sig::canonical_request(
+            method,
+            &path,
+            &query,
+            &[("host", host)],
+            sig::UNSIGNED_PAYLOAD,
+        );
+        let signature = sig::sign(
+            &self.secret_key_for(access_key),
+            &amz_date,
+            &scope,
+            &canonical,
+        );
+
+        let query: Vec<String> = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", sig::encode(k), sig::encode(v)))
+            .collect();
+
+        format!(
+            "{}{}?{}&X-Amz-Signature={}",
+            endpoint,
+            path,
+            query.join("&"),
+            signature
+        )
+    }
+
+    /// The fields which let a browser form POST uploads meeting the `conditions`,
+    /// until it `expires`; the form also needs the fields the conditions cover, then the `file`.
+    pub fn post_policy(
+        &self,
+        access_key: &str,
+        region: &str,
+        conditions: &[Condition],
+        expires: Duration,
+        now: DateTime<Utc>,
+    ) -> Vec<(&'static str, String)> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = format!("{}/{}/s3", now.format("%Y%m%d"), region);
+        let credential = format!("{}/{}/aws4_request", access_key, scope);
+
+        let mut conditions = conditions.to_vec();
+        for (name, value) in &[
+            ("x-amz-algorithm", sig::ALGORITHM),
+            ("x-amz-credential", credential.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ] {
+            conditions.push(Condition::Eq(name.to_string(), value.to_string()));
+        }
+
+        let policy = post::Policy {
+            expiration: now + expires,
+            conditions,
+        };
+        let policy = base64::encode(&policy.to_json());
+        let signature = sig::sign_policy(&self.secret_key_for(access_key), &scope, &policy);
+
+        vec![
+            ("policy", policy),
+            ("x-amz-algorithm", sig::ALGORITHM.to_string()),
+            ("x-amz-credential", credential),
+            ("x-amz-date", amz_date),
+            ("x-amz-signature", signature),
+        ]
+    }
 }
 
 impl RoleId {
@@ -64,6 +175,12 @@ impl RoleId {
     }
 }
 
+impl fmt::Display for RoleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
 fn pack(values: &[u8]) -> String {
     base64::encode_config(values, base64::URL_SAFE_NO_PAD)
 }
@@ -76,7 +193,7 @@ fn mac(key: &[u8], value: &[u8]) -> [u8; 32] {
     use hmac::Mac;
     let mut mac = hmac::Hmac::<sha2::Sha512Trunc256>::new_varkey(key).expect("valid key");
     mac.input(value);
-    mac.result().code().try_into().expect("valid output size")
+    mac.result().code().into()
 }
 
 #[test]
@@ -109,3 +226,99 @@ fn key_derivation() {
     assert_eq!("S1u1SLAQIDBAUGAQIDBAUH", &access[..2 + 4 + 16]);
     assert_eq!(role_id, master.parse_access(&access).expect("test data"));
 }
+
+#[test]
+fn presigning() {
+    use chrono::offset::TimeZone as _;
+
+    let master = MasterKey::new("");
+    let access = master.access_key_for(RoleId::random());
+    let now = Utc.ymd(2020, 1, 4).and_hms(22, 23, 24);
+
+    let url = master.presigned_url(
+        &access,
+        "eu-west-2",
+        "GET",
+        "http://localhost:8202/potato/a b",
+        Duration::hours(1),
+        now,
+    );
+    assert!(url.starts_with("http://localhost:8202/potato/a%20b?X-Amz-Algorithm=AWS4-HMAC-SHA256&"));
+
+    let decode = |v: &str| {
+        percent_encoding::percent_decode_str(v)
+            .decode_utf8()
+            .expect("utf-8")
+            .to_string()
+    };
+    let query = &url[url.find('?').expect("has query") + 1..];
+    let params = query
+        .split('&')
+        .map(|pair| {
+            let eq = pair.find('=').expect("all pairs");
+            (decode(&pair[..eq]), decode(&pair[eq + 1..]))
+        })
+        .collect();
+
+    let headers = maplit::hashmap! { "host".to_string() => "localhost:8202".to_string() };
+    let regions = vec!["us-east-1".to_string(), "eu-west-2".to_string()];
+    let validate = |at| {
+        sig::validate_query(
+            "GET",
+            "/potato/a%20b",
+            &params,
+            &regions,
+            |access| master.secret_key_for(access),
+            at,
+            headers.clone(),
+        )
+    };
+
+    assert_eq!(
+        sig::Validation::Valid(access.to_string(), headers.clone()),
+        validate(now + Duration::minutes(5))
+    );
+    assert_eq!(
+        sig::Validation::Invalid,
+        validate(now + Duration::minutes(61))
+    );
+}
+
+#[test]
+fn post_policy() {
+    use chrono::offset::TimeZone as _;
+
+    let master = MasterKey::new("");
+    let access = master.access_key_for(RoleId::random());
+    let now = Utc.ymd(2020, 1, 4).and_hms(22, 23, 24);
+
+    let conditions = [
+        Condition::Eq("bucket".to_string(), "potato".to_string()),
+        Condition::StartsWith("key".to_string(), "uploads/".to_string()),
+        Condition::ContentLengthRange(1, 1024),
+    ];
+    let mut fields: std::collections::HashMap<String, String> = master
+        .post_policy(&access, "eu-west-2", &conditions, Duration::hours(1), now)
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    fields.insert("key".to_string(), "uploads/${filename}".to_string());
+
+    let regions = vec!["us-east-1".to_string(), "eu-west-2".to_string()];
+    let validate = |bucket, at| {
+        post::validate(
+            bucket,
+            &fields,
+            &regions,
+            |access| master.secret_key_for(access),
+            at,
+        )
+    };
+
+    let (user, policy) = validate("potato", now + Duration::minutes(5)).expect("valid");
+    assert_eq!(access, user);
+    assert_eq!((1, 1024), policy.length_range());
+
+    assert!(validate("carrot", now).is_err());
+    assert!(validate("potato", now + Duration::minutes(61)).is_err());
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use serde_derive::Deserialize;
+
+use super::bucket::VersioningPolicy;
+use super::dir;
+use super::xml::Xml;
+
+const MAX_KEYS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Configuration {
+    status: Option<String>,
+}
+
+/// Parse the body of a PutBucketVersioning request, if it's acceptable.
+pub fn from_xml(body: &[u8]) -> Option<VersioningPolicy> {
+    let config: Configuration = serde_xml_rs::from_reader(body).ok()?;
+    match config.status?.as_str() {
+        "Enabled" => Some(VersioningPolicy::On),
+        "Suspended" => Some(VersioningPolicy::Suspended),
+        _ => None,
+    }
+}
+
+/// Render a GetBucketVersioning response; buckets which have never been versioned have no status.
+pub fn configuration(versioning: VersioningPolicy) -> String {
+    let mut xml = Xml::new("VersioningConfiguration");
+    match versioning {
+        VersioningPolicy::Off => (),
+        VersioningPolicy::On => xml.text("Status", "Enabled"),
+        VersioningPolicy::Suspended => xml.text("Status", "Suspended"),
+    }
+    xml.finish()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub key: String,
+    pub id: usize,
+    pub latest: bool,
+    pub delete_marker: bool,
+    pub modified: DateTime<Utc>,
+    pub size: u64,
+    pub etag: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    prefix: String,
+    max_keys: usize,
+    key_marker: Option<String>,
+    version_id_marker: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Page {
+    versions: Vec<Version>,
+    /// The last version returned, if there are more.
+    next: Option<(String, usize)>,
+}
+
+impl Query {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Query, &'static str> {
+        let max_keys = match params.get("max-keys") {
+            Some(max) => max.parse::<usize>().map_err(|_| "invalid max-keys")?,
+            None => MAX_KEYS,
+        };
+
+        let key_marker = params.get("key-marker").filter(|k| !k.is_empty()).cloned();
+
+        let version_id_marker = match params.get("version-id-marker") {
+            Some(_) if key_marker.is_none() => return Err("version-id-marker without key-marker"),
+            Some(id) => Some(
+                id.parse::<usize>()
+                    .map_err(|_| "invalid version-id-marker")?,
+            ),
+            None => None,
+        };
+
+        Ok(Query {
+            prefix: params.get("prefix").cloned().unwrap_or_default(),
+            max_keys: max_keys.min(MAX_KEYS),
+            key_marker,
+            version_id_marker,
+        })
+    }
+
+    /// Whether the previous page included this version.
+    fn passed(&self, version: &Version) -> bool {
+        let marker = match &self.key_marker {
+            Some(marker) => marker,
+            None => return false,
+        };
+        if version.key != *marker {
+            return version.key < *marker;
+        }
+        match self.version_id_marker {
+            // versions are newest (highest id) first
+            Some(id) => version.id >= id,
+            None => true,
+        }
+    }
+}
+
+/// Every version of every key in a bucket's store, sorted by key, then newest first.
+pub async fn versions(root: &Path) -> Result<Vec<Version>, Error> {
+    let mut metas = dir::list(root).await?;
+    metas.sort_by(|left, right| left.key().cmp(right.key()));
+
+    let mut ret = Vec::new();
+    for meta in metas {
+        let latest = meta.latest_version_id()?;
+        for (id, version) in meta.versions() {
+            ret.push(Version {
+                key: meta.key().to_string(),
+                id,
+                latest: latest == id,
+                delete_marker: version.tombstone(),
+                modified: version.modified(),
+                size: version.content_length(),
+                etag: version.etag()?,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+fn page(versions: Vec<Version>, query: &Query) -> Page {
+    let mut ret = Vec::new();
+    let mut truncated = false;
+
+    for version in versions {
+        if !version.key.starts_with(&query.prefix) || query.passed(&version) {
+            continue;
+        }
+
+        if ret.len() >= query.max_keys {
+            truncated = true;
+            break;
+        }
+
+        ret.push(version);
+    }
+
+    let next = match ret.last() {
+        Some(last) if truncated => Some((last.key.to_string(), last.id)),
+        _ => None,
+    };
+
+    Page {
+        versions: ret,
+        next,
+    }
+}
+
+/// Render a ListObjectVersions response.
+pub fn list_versions(bucket: &str, versions: Vec<Version>, query: &Query) -> String {
+    let page = page(versions, query);
+
+    let mut xml = Xml::new("ListVersionsResult");
+    xml.text("Name", bucket);
+    xml.text("Prefix", &query.prefix);
+    xml.text("KeyMarker", query.key_marker.as_deref().unwrap_or(""));
+    match query.version_id_marker {
+        Some(id) => xml.text("VersionIdMarker", id),
+        None => xml.text("VersionIdMarker", ""),
+    }
+    xml.text("MaxKeys", query.max_keys);
+    xml.text("IsTruncated", page.next.is_some());
+    if let Some((key, id)) = &page.next {
+        xml.text("NextKeyMarker", key);
+        xml.text("NextVersionIdMarker", id);
+    }
+
+    for version in &page.versions {
+        let tag = if version.delete_marker {
+            "DeleteMarker"
+        } else {
+            "Version"
+        };
+        xml.open(tag);
+        xml.text("Key", &version.key);
+        xml.text("VersionId", version.id);
+        xml.text("IsLatest", version.latest);
+        xml.text(
+            "LastModified",
+            version.modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        );
+        if !version.delete_marker {
+            xml.text("ETag", &version.etag);
+            xml.text("Size", version.size);
+            xml.text("StorageClass", "STANDARD");
+        }
+        xml.close(tag);
+    }
+
+    xml.finish()
+}
+
+#[test]
+fn parse() {
+    assert_eq!(
+        Some(VersioningPolicy::On),
+        from_xml(
+            br#"<VersioningConfiguration xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+            <Status>Enabled</Status>
+            </VersioningConfiguration>"#
+        )
+    );
+    assert_eq!(
+        Some(VersioningPolicy::Suspended),
+        from_xml(b"<VersioningConfiguration><Status>Suspended</Status></VersioningConfiguration>")
+    );
+    assert_eq!(
+        None,
+        from_xml(b"<VersioningConfiguration></VersioningConfiguration>")
+    );
+    assert_eq!(
+        None,
+        from_xml(b"<VersioningConfiguration><Status>Off</Status></VersioningConfiguration>")
+    );
+}
+
+#[test]
+fn paging() {
+    let versions: Vec<Version> = [("a", 0), ("b", 2), ("b", 1), ("b", 0), ("c", 0)]
+        .iter()
+        .map(|(key, id)| Version {
+            key: key.to_string(),
+            id: *id,
+            latest: false,
+            delete_marker: false,
+            modified: Utc::now(),
+            size: 0,
+            etag: String::new(),
+        })
+        .collect();
+
+    let ids = |page: &Page| -> Vec<(String, usize)> {
+        page.versions
+            .iter()
+            .map(|v| (v.key.to_string(), v.id))
+            .collect()
+    };
+
+    let mut query = Query::from_params(&HashMap::new()).expect("static");
+    query.max_keys = 2;
+
+    let first = page(versions.clone(), &query);
+    assert_eq!(
+        vec![("a".to_string(), 0), ("b".to_string(), 2)],
+        ids(&first)
+    );
+    assert_eq!(Some(("b".to_string(), 2)), first.next);
+
+    let (key, id) = first.next.expect("truncated");
+    query.key_marker = Some(key);
+    query.version_id_marker = Some(id);
+    let second = page(versions.clone(), &query);
+    assert_eq!(
+        vec![("b".to_string(), 1), ("b".to_string(), 0)],
+        ids(&second)
+    );
+
+    query.version_id_marker = None;
+    let after_key = page(versions, &query);
+    assert_eq!(vec![("c".to_string(), 0)], ids(&after_key));
+    assert_eq!(None, after_key.next);
+}
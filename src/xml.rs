@@ -0,0 +1,77 @@
+use std::fmt::Display;
+
+const NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+
+/// Just enough of an xml writer for S3's flat responses.
+pub struct Xml {
+    root: &'static str,
+    out: String,
+}
+
+impl Xml {
+    pub fn new(root: &'static str) -> Xml {
+        let mut out = String::with_capacity(4 * 1024);
+        out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str(&format!("<{} xmlns=\"{}\">", root, NAMESPACE));
+        Xml { root, out }
+    }
+
+    pub fn open(&mut self, tag: &str) {
+        self.out.push('<');
+        self.out.push_str(tag);
+        self.out.push('>');
+    }
+
+    pub fn close(&mut self, tag: &str) {
+        self.out.push_str("</");
+        self.out.push_str(tag);
+        self.out.push('>');
+    }
+
+    pub fn text(&mut self, tag: &str, value: impl Display) {
+        self.open(tag);
+        self.content(value);
+        self.close(tag);
+    }
+
+    /// Text directly inside the current element, e.g. the root.
+    pub fn content(&mut self, value: impl Display) {
+        self.out.push_str(&escape(&value.to_string()));
+    }
+
+    pub fn finish(mut self) -> String {
+        let root = self.root;
+        self.close(root);
+        self.out
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&apos;"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[test]
+fn writing() {
+    let mut xml = Xml::new("Foo");
+    xml.open("Bar");
+    xml.text("Baz", "<\"a&b\">");
+    xml.text("Quux", 5);
+    xml.close("Bar");
+    assert_eq!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Foo xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+         <Bar><Baz>&lt;&quot;a&amp;b&quot;&gt;</Baz><Quux>5</Quux></Bar></Foo>",
+        xml.finish()
+    );
+}